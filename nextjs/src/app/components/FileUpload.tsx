@@ -4,7 +4,6 @@ import { useDropzone } from "react-dropzone";
 import ProcessBtn from "./ProcessBtn";
 import { toast } from "react-toastify";
 import "react-toastify/dist/ReactToastify.css";
-import { sha3_256 } from "js-sha3";
 import { FontAwesomeIcon } from "@fortawesome/react-fontawesome";
 import { faTrash } from "@fortawesome/free-solid-svg-icons";
 
@@ -278,11 +277,24 @@ export default function FileUpload() {
     }
     formData.append("keep_aspect_ratio", keepAspectRatio.toString());
 
-    //  Generate session ID
-    const userCounter = 1; // Placeholder
-    const rawString = `User-${userCounter}`;
-    const sessionID = `session-${sha3_256(rawString)}`;
-    console.log("Generated Session ID:", sessionID);
+    //  Request a session ID from the Rust engine
+    let sessionID: string;
+    try {
+      const sessionResponse = await fetch(
+        `${process.env.NEXT_PUBLIC_RUST_ENGINE_URL}/sessions`,
+        { method: "POST", mode: "cors" },
+      );
+      if (!sessionResponse.ok) {
+        throw new Error(`Session request failed: ${sessionResponse.status}`);
+      }
+      ({ session_id: sessionID } = await sessionResponse.json());
+    } catch (error) {
+      console.error("Failed to get session ID:", error);
+      toast.error("Could not reach the conversion server. Please try again.");
+      setLoading(false);
+      return;
+    }
+    console.log("Session ID:", sessionID);
 
     setProgress(20); // Preparing upload
 
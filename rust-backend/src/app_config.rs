@@ -0,0 +1,479 @@
+use crate::color::ColorProfileMode;
+use crate::error::EngineError;
+use crate::image_utils::{BatchOptions, DecodeLimits, FormatCandidate};
+use crate::metadata::MetadataPolicy;
+use crate::scheduler::MemoryScheduler;
+use config::{Config, ConfigError, Environment, File};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+/// Formats the engine can encode to.
+pub const SUPPORTED_FORMATS: [&str; 5] = ["jpg", "png", "webp", "avif", "bmp"];
+
+/// Requested format that picks the smallest output of several candidates.
+pub const AUTO_FORMAT: &str = "auto";
+
+/// Formats `format=auto` may choose from.
+pub const AUTO_CANDIDATE_FORMATS: [&str; 4] = ["jpg", "png", "webp", "avif"];
+
+// Plain env variables from before the config file existed, mapped onto config keys
+const LEGACY_ENV: [(&str, &str); 12] = [
+    ("SERVER_IP", "server.ip"),
+    ("SERVER_PORT", "server.port"),
+    ("ALLOWED_ORIGIN", "server.allowed_origin"),
+    ("SHUTDOWN_TIMEOUT_SECS", "server.shutdown_timeout_secs"),
+    ("MAX_FILES", "limits.max_files"),
+    ("MAX_FILE_SIZE", "limits.max_file_size_mb"),
+    ("MAX_IMAGE_WIDTH", "limits.max_image_width"),
+    ("MAX_IMAGE_HEIGHT", "limits.max_image_height"),
+    ("MAX_MEGAPIXELS", "limits.max_megapixels"),
+    ("MAX_DECODE_MEMORY", "limits.max_decode_memory_mb"),
+    ("JOB_RESULT_TTL_SECS", "jobs.result_ttl_secs"),
+    ("SESSION_TTL_SECS", "sessions.ttl_secs"),
+];
+
+/// Engine configuration, loaded once at startup.
+///
+/// Sources, lowest priority first: built-in defaults, the config file (`CONFIG_FILE`, or
+/// `config.toml` / `config.yaml` in the working directory), `CONVERTXION_<SECTION>__<KEY>`
+/// variables, and finally the plain variables such as `SERVER_PORT` or `MAX_FILES`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub limits: LimitsConfig,
+    pub engine: EngineConfig,
+    pub defaults: DefaultsConfig,
+    pub jobs: JobsConfig,
+    pub sessions: SessionsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub ip: String,
+    pub port: u16,
+    pub allowed_origin: String,
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ip: "127.0.0.1".to_string(),
+            port: 8080,
+            allowed_origin: "http://localhost:3000".to_string(),
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub max_files: usize,
+    pub max_file_size_mb: usize,
+    pub allowed_formats: Vec<String>,
+    pub max_image_width: u32,
+    pub max_image_height: u32,
+    pub max_megapixels: f64,
+    pub max_decode_memory_mb: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_files: 3,
+            max_file_size_mb: 12,
+            allowed_formats: SUPPORTED_FORMATS.iter().map(|f| f.to_string()).collect(),
+            max_image_width: 16384,
+            max_image_height: 16384,
+            max_megapixels: 100.0,
+            max_decode_memory_mb: 512,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn max_file_size(&self) -> usize {
+        self.max_file_size_mb * 1024 * 1024
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EngineConfig {
+    /// Worker threads per batch; 0 picks the physical core count (at least 2).
+    pub threads: usize,
+    /// Estimated decode and encode memory all running conversions may hold together, in MB.
+    pub memory_budget_mb: u64,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            threads: 0,
+            memory_budget_mb: 1024,
+        }
+    }
+}
+
+/// Settings used when a request leaves them out; quality and compression are keyed by format.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DefaultsConfig {
+    pub quality: HashMap<String, u8>,
+    pub compression: HashMap<String, u8>,
+    /// Rotate or flip images according to their EXIF Orientation tag.
+    pub auto_orient: bool,
+    /// Metadata written into converted files: `strip`, `keep` or `safe`.
+    pub metadata: MetadataPolicy,
+    /// Embedded ICC profiles: `srgb` converts the pixels, `embed` keeps the profile.
+    pub color_profile: ColorProfileMode,
+    /// Candidates tried by `format=auto`; the smallest output wins.
+    pub auto_formats: Vec<String>,
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        DefaultsConfig {
+            quality: HashMap::from([
+                ("jpg".to_string(), 80),
+                ("webp".to_string(), 80),
+                ("avif".to_string(), 80),
+            ]),
+            compression: HashMap::from([
+                ("png".to_string(), 2),
+                ("jpg".to_string(), 8),
+                ("avif".to_string(), 8),
+            ]),
+            auto_orient: true,
+            metadata: MetadataPolicy::Strip,
+            color_profile: ColorProfileMode::Srgb,
+            auto_formats: vec!["avif".to_string(), "webp".to_string(), "jpg".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    pub result_ttl_secs: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            result_ttl_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionsConfig {
+    pub ttl_secs: u64,
+    /// Most live sessions at once; beyond that new ones are refused with 503.
+    pub max_sessions: usize,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        SessionsConfig {
+            ttl_secs: 3600,
+            max_sessions: 10_000,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Loads and validates the configuration from all sources.
+    pub fn load() -> Result<Self, ConfigError> {
+        let file = match env::var("CONFIG_FILE") {
+            Ok(path) => File::with_name(&path),
+            Err(_) => File::with_name("config").required(false),
+        };
+
+        let mut builder = Config::builder().add_source(file).add_source(
+            Environment::with_prefix("CONVERTXION")
+                .prefix_separator("_")
+                .separator("__")
+                .list_separator(",")
+                .with_list_parse_key("limits.allowed_formats")
+                .with_list_parse_key("defaults.auto_formats")
+                .try_parsing(true),
+        );
+        for (var, key) in LEGACY_ENV {
+            builder = builder.set_override_option(key, env::var(var).ok())?;
+        }
+        if let Ok(formats) = env::var("ALLOWED_FORMATS") {
+            let formats: Vec<String> = formats.split(',').map(|s| s.trim().to_string()).collect();
+            builder = builder.set_override("limits.allowed_formats", formats)?;
+        }
+
+        Self::from_config(builder.build()?)
+    }
+
+    // Deserializes merged sources, fills in per-format defaults and validates the result
+    fn from_config(source: Config) -> Result<Self, ConfigError> {
+        let mut config: AppConfig = source.try_deserialize()?;
+        // A partial [defaults.*] table replaces the whole map, so fill the gaps back in
+        let builtin = DefaultsConfig::default();
+        for (format, quality) in builtin.quality {
+            config.defaults.quality.entry(format).or_insert(quality);
+        }
+        for (format, compression) in builtin.compression {
+            config
+                .defaults
+                .compression
+                .entry(format)
+                .or_insert(compression);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Message(message));
+
+        if self.server.port == 0 {
+            return invalid("server.port must not be 0".to_string());
+        }
+        if self.limits.max_files == 0 || self.limits.max_file_size_mb == 0 {
+            return invalid(
+                "limits.max_files and limits.max_file_size_mb must be at least 1".into(),
+            );
+        }
+        if self.sessions.max_sessions == 0 {
+            return invalid("sessions.max_sessions must be at least 1".to_string());
+        }
+        if self.limits.allowed_formats.is_empty() {
+            return invalid("limits.allowed_formats must not be empty".to_string());
+        }
+        if let Some(format) = self
+            .limits
+            .allowed_formats
+            .iter()
+            .find(|f| !SUPPORTED_FORMATS.contains(&f.as_str()))
+        {
+            return invalid(format!(
+                "limits.allowed_formats: unsupported format '{}'",
+                format
+            ));
+        }
+        if self.defaults.auto_formats.is_empty() {
+            return invalid("defaults.auto_formats must not be empty".to_string());
+        }
+        if let Some(format) = self
+            .defaults
+            .auto_formats
+            .iter()
+            .find(|f| !AUTO_CANDIDATE_FORMATS.contains(&f.as_str()))
+        {
+            return invalid(format!(
+                "defaults.auto_formats: '{}' cannot be chosen automatically (expected {})",
+                format,
+                AUTO_CANDIDATE_FORMATS.join(", ")
+            ));
+        }
+        if self.limits.max_megapixels <= 0.0 {
+            return invalid("limits.max_megapixels must be positive".to_string());
+        }
+        if let Some((format, quality)) = self
+            .defaults
+            .quality
+            .iter()
+            .find(|(_, q)| !(1..=100).contains(*q))
+        {
+            return invalid(format!(
+                "defaults.quality.{} must be between 1 and 100, got {}",
+                format, quality
+            ));
+        }
+        // Images the decoder accepts should not all be turned away by the scheduler
+        if self.engine.memory_budget_mb < self.limits.max_decode_memory_mb {
+            return invalid(format!(
+                "engine.memory_budget_mb ({}) must be at least limits.max_decode_memory_mb ({})",
+                self.engine.memory_budget_mb, self.limits.max_decode_memory_mb
+            ));
+        }
+        Ok(())
+    }
+
+    /// Logs the effective configuration once at startup.
+    pub fn log_summary(&self) {
+        info!("⚙️ Configuration loaded");
+        info!(
+            "   Server: {}:{} (origin {}, shutdown timeout {}s)",
+            self.server.ip,
+            self.server.port,
+            self.server.allowed_origin,
+            self.server.shutdown_timeout_secs
+        );
+        info!(
+            "   Limits: {} files x {} MB, formats [{}], {}x{} px, {} MP, {} MB decode",
+            self.limits.max_files,
+            self.limits.max_file_size_mb,
+            self.limits.allowed_formats.join(", "),
+            self.limits.max_image_width,
+            self.limits.max_image_height,
+            self.limits.max_megapixels,
+            self.limits.max_decode_memory_mb
+        );
+        info!(
+            "   Engine: {} threads, {} MB memory budget",
+            match self.engine.threads {
+                0 => "auto".to_string(),
+                n => n.to_string(),
+            },
+            self.engine.memory_budget_mb
+        );
+        info!(
+            "   Defaults: quality {:?}, compression {:?}, auto-orient {}, metadata {:?}, color profile {:?}, auto formats [{}]",
+            self.defaults.quality,
+            self.defaults.compression,
+            self.defaults.auto_orient,
+            self.defaults.metadata,
+            self.defaults.color_profile,
+            self.defaults.auto_formats.join(", ")
+        );
+        info!(
+            "   Jobs kept {}s, sessions expire after {}s (at most {})",
+            self.jobs.result_ttl_secs, self.sessions.ttl_secs, self.sessions.max_sessions
+        );
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_width: self.limits.max_image_width,
+            max_height: self.limits.max_image_height,
+            max_megapixels: self.limits.max_megapixels,
+            max_decode_memory: self.limits.max_decode_memory_mb * 1024 * 1024,
+        }
+    }
+
+    pub fn memory_budget(&self) -> u64 {
+        self.engine.memory_budget_mb * 1024 * 1024
+    }
+
+    /// Batch options drawing on `memory`, the scheduler built from [`Self::memory_budget`].
+    pub fn batch_options(&self, memory: Arc<MemoryScheduler>) -> BatchOptions {
+        BatchOptions {
+            threads: self.engine.threads,
+            memory,
+        }
+    }
+
+    pub fn default_quality(&self, format: &str) -> Option<u8> {
+        self.defaults.quality.get(format).copied()
+    }
+
+    pub fn default_compression(&self, format: &str) -> Option<u8> {
+        self.defaults.compression.get(format).copied()
+    }
+
+    /// Candidates for `format=auto`: `formats` (else `defaults.auto_formats`) that are
+    /// allowed, each with the requested quality and compression or its own defaults.
+    /// Fails when a format cannot be chosen automatically or none is allowed.
+    pub fn format_candidates(
+        &self,
+        formats: Option<&[String]>,
+        quality: Option<u8>,
+        compression: Option<u8>,
+    ) -> Result<Vec<FormatCandidate>, EngineError> {
+        let formats = formats.unwrap_or(&self.defaults.auto_formats);
+        if let Some(format) = formats
+            .iter()
+            .find(|f| !AUTO_CANDIDATE_FORMATS.contains(&f.as_str()))
+        {
+            return Err(EngineError::UnsupportedFormat(format!(
+                "'{}' cannot be chosen by format=auto (expected {})",
+                format,
+                AUTO_CANDIDATE_FORMATS.join(", ")
+            )));
+        }
+
+        let candidates: Vec<FormatCandidate> = formats
+            .iter()
+            .filter(|format| self.limits.allowed_formats.contains(format))
+            .map(|format| FormatCandidate {
+                format: format.clone(),
+                quality: quality.or_else(|| self.default_quality(format)),
+                compression: compression.or_else(|| self.default_compression(format)),
+            })
+            .collect();
+        if candidates.is_empty() {
+            return Err(EngineError::UnsupportedFormat(format!(
+                "none of the auto formats [{}] is allowed. Allowed formats: {}",
+                formats.join(", "),
+                self.limits.allowed_formats.join(", ")
+            )));
+        }
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    fn parse(toml: &str) -> Result<AppConfig, ConfigError> {
+        AppConfig::from_config(
+            Config::builder()
+                .add_source(File::from_str(toml, FileFormat::Toml))
+                .build()?,
+        )
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = parse(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.limits.allowed_formats.len(), SUPPORTED_FORMATS.len());
+        assert_eq!(config.sessions.max_sessions, 10_000);
+    }
+
+    #[test]
+    fn partial_files_keep_the_builtin_defaults() {
+        let config = parse("[limits]\nmax_files = 7\n\n[defaults.quality]\njpg = 60\n").unwrap();
+        assert_eq!(config.limits.max_files, 7);
+        assert_eq!(config.limits.max_file_size_mb, 12);
+        assert_eq!(config.default_quality("jpg"), Some(60));
+        // The other formats of a partial table fall back to the built-in values
+        assert_eq!(config.default_quality("webp"), Some(80));
+        assert_eq!(config.default_compression("png"), Some(2));
+        assert_eq!(config.default_quality("png"), None);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for toml in [
+            "[server]\nport = 0",
+            "[limits]\nmax_files = 0",
+            "[limits]\nallowed_formats = []",
+            "[limits]\nallowed_formats = [\"gif\"]",
+            "[limits]\nmax_megapixels = 0.0",
+            "[defaults]\nauto_formats = [\"bmp\"]",
+            "[defaults.quality]\njpg = 0",
+            "[engine]\nmemory_budget_mb = 100",
+            "[sessions]\nmax_sessions = 0",
+        ] {
+            assert!(parse(toml).is_err(), "accepted {:?}", toml);
+        }
+    }
+
+    #[test]
+    fn limits_and_budget_are_converted_to_bytes() {
+        let config = AppConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.limits.max_file_size(), 12 * 1024 * 1024);
+        assert_eq!(config.memory_budget(), 1024 * 1024 * 1024);
+        let limits = config.decode_limits();
+        assert_eq!(limits.max_width, 16384);
+        assert_eq!(limits.max_decode_memory, 512 * 1024 * 1024);
+    }
+}
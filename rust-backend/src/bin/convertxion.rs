@@ -0,0 +1,507 @@
+use anyhow::{bail, Context};
+use clap::Parser;
+use convertxion_engine::app_config::{AppConfig, AUTO_FORMAT};
+use convertxion_engine::color::ProfileAction;
+use convertxion_engine::error::EngineError;
+use convertxion_engine::image_utils::{
+    convert_upload, output_filename, ConvertedUpload, ImageSettings, UploadSettings, WebpOptions,
+};
+use convertxion_engine::resize::{self, ResizeMode};
+use convertxion_engine::ssim;
+use convertxion_engine::transform::Transform;
+use env_logger::Env;
+use image::{ImageFormat, Rgba};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::Instant;
+use zip::write::{SimpleFileOptions, ZipWriter};
+
+/// Batch-convert local images with the same encoders and presets as the ConvertXion engine.
+#[derive(Parser)]
+#[command(name = "convertxion", version)]
+struct Args {
+    /// Input files, directories or glob patterns (quote globs, e.g. "photos/*.png")
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// Target format; auto keeps the smallest of the --auto-formats candidates
+    #[arg(short, long, value_parser = ["jpg", "png", "webp", "avif", "bmp", "auto"])]
+    format: String,
+
+    /// Candidates for --format auto, e.g. webp,jpg (default: defaults.auto_formats from the
+    /// config, else avif,webp,jpg)
+    #[arg(long, value_delimiter = ',', value_parser = ["jpg", "png", "webp", "avif"])]
+    auto_formats: Vec<String>,
+
+    /// Encoder quality (1-100)
+    #[arg(short, long)]
+    quality: Option<u8>,
+
+    /// Largest output size in bytes (jpg, webp, avif); the quality is lowered until it fits
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_bytes: Option<u64>,
+
+    /// With --max-bytes, shrink images that do not fit even at quality 1
+    #[arg(long, requires = "max_bytes")]
+    downscale_to_fit: bool,
+
+    /// Pick the lowest quality whose SSIM against the resized image reaches this target
+    /// (jpg, webp; e.g. 0.98) instead of using --quality. Not available for avif, which the
+    /// engine cannot decode to measure
+    #[arg(long, value_parser = parse_ssim)]
+    target_ssim: Option<f64>,
+
+    /// Compression level (PNG: 1-3, JPEG: 1-3, AVIF: speed 1-10)
+    #[arg(short, long)]
+    compression: Option<u8>,
+
+    /// AVIF bit depth (8 or 10)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(8..=10))]
+    bit_depth: Option<u8>,
+
+    /// Encode WebP losslessly; --quality then sets the compression effort
+    #[arg(long)]
+    lossless: bool,
+
+    /// WebP near-lossless preprocessing, 0 (strongest) to 100 (off); implies --lossless
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    near_lossless: Option<u8>,
+
+    /// Quality of the WebP alpha plane in lossy mode (0-100, default 100)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    alpha_quality: Option<u8>,
+
+    /// WebP speed against size, 0 (fastest) to 6 (smallest) (default 4)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=6))]
+    method: Option<u8>,
+
+    /// Keep the color of fully transparent pixels in WebP output
+    #[arg(long)]
+    exact: bool,
+
+    /// Crop, rotate or flip before resizing, in the order given (repeatable):
+    /// crop:X,Y,WIDTH,HEIGHT, crop:16:9[,GRAVITY], rotate:DEGREES[,RRGGBB], flip:h, flip:v
+    #[arg(long = "transform", value_name = "OP", value_parser = parse_transform)]
+    transforms: Vec<Transform>,
+
+    /// Resize to WIDTHxHEIGHT
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_resize)]
+    resize: Option<(u32, u32)>,
+
+    /// Keep the aspect ratio when resizing (same as --fit contain)
+    #[arg(long, conflicts_with = "fit")]
+    keep_aspect: bool,
+
+    /// How the image is fitted into --resize: fill stretches, contain fits inside,
+    /// cover crops to the exact size, pad letterboxes, inside never enlarges
+    #[arg(long, value_parser = ["fill", "contain", "cover", "pad", "inside"])]
+    fit: Option<String>,
+
+    /// Anchor for the cover crop and pad placement
+    #[arg(long, default_value = "center", value_parser = [
+        "center", "north", "south", "east", "west",
+        "northeast", "northwest", "southeast", "southwest",
+    ])]
+    gravity: String,
+
+    /// Letterbox color for --fit pad, as RRGGBB, RRGGBBAA or a name such as black
+    #[arg(long, default_value = "#ffffff", value_parser = parse_color)]
+    background: Rgba<u8>,
+
+    /// Apply the EXIF Orientation tag even if defaults.auto_orient is off in the config
+    #[arg(long, conflicts_with = "no_auto_orient")]
+    auto_orient: bool,
+
+    /// Keep the stored pixel orientation instead of applying the EXIF Orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+
+    /// Metadata to carry over: strip, keep, or safe (copyright and orientation)
+    /// (default: defaults.metadata from the config, else strip)
+    #[arg(long, value_parser = ["strip", "keep", "safe"])]
+    metadata: Option<String>,
+
+    /// Embedded ICC profiles: srgb converts the pixels, embed keeps the profile
+    /// (default: defaults.color_profile from the config, else srgb)
+    #[arg(long, value_parser = ["srgb", "embed"])]
+    color_profile: Option<String>,
+
+    /// Output directory, or a path ending in .zip to write a single archive
+    #[arg(short, long, default_value = "converted")]
+    output: PathBuf,
+
+    /// Number of parallel workers (default: engine.threads from the config, else the
+    /// physical core count, at least 2)
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+impl Args {
+    /// `--auto-orient` / `--no-auto-orient` win over the config default.
+    fn auto_orient(&self, default: bool) -> bool {
+        match (self.auto_orient, self.no_auto_orient) {
+            (true, _) => true,
+            (_, true) => false,
+            _ => default,
+        }
+    }
+}
+
+fn parse_resize(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value
+        .split_once(['x', ','])
+        .ok_or_else(|| "expected WIDTHxHEIGHT, e.g. 800x600".to_string())?;
+    let width = width.trim().parse().map_err(|e| format!("width: {}", e))?;
+    let height = height
+        .trim()
+        .parse()
+        .map_err(|e| format!("height: {}", e))?;
+    if width == 0 || height == 0 {
+        return Err("width and height must be at least 1".to_string());
+    }
+    Ok((width, height))
+}
+
+fn parse_transform(value: &str) -> Result<Transform, String> {
+    value.parse().map_err(|e: EngineError| e.to_string())
+}
+
+fn parse_ssim(value: &str) -> Result<f64, String> {
+    ssim::parse_target(value).map_err(|e| e.to_string())
+}
+
+fn parse_color(value: &str) -> Result<Rgba<u8>, String> {
+    resize::parse_color(value).map_err(|e| e.to_string())
+}
+
+// Where converted files end up
+struct Output {
+    target: Target,
+    // Names written so far, so no file replaces another one
+    written: Mutex<HashSet<String>>,
+}
+
+enum Target {
+    Directory(PathBuf),
+    Zip(Box<Mutex<ZipWriter<File>>>),
+}
+
+impl Output {
+    fn create(path: &Path) -> anyhow::Result<Self> {
+        let target = if path.extension().is_some_and(|ext| ext == "zip") {
+            let file =
+                File::create(path).with_context(|| format!("Cannot create {}", path.display()))?;
+            Target::Zip(Box::new(Mutex::new(ZipWriter::new(file))))
+        } else {
+            fs::create_dir_all(path)
+                .with_context(|| format!("Cannot create {}", path.display()))?;
+            Target::Directory(path.to_path_buf())
+        };
+        Ok(Output {
+            target,
+            written: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn write(&self, filename: &str, data: &[u8]) -> Result<(), EngineError> {
+        if !self.written.lock().unwrap().insert(filename.to_string()) {
+            return Err(EngineError::InvalidRequest(format!(
+                "More than one input would be written to '{}'",
+                filename
+            )));
+        }
+        match &self.target {
+            Target::Directory(dir) => fs::write(dir.join(filename), data)
+                .map_err(|e| EngineError::Internal(format!("Cannot write {}: {}", filename, e))),
+            Target::Zip(zip) => {
+                let mut zip = zip.lock().unwrap();
+                zip.start_file(filename, SimpleFileOptions::default())
+                    .map_err(EngineError::archive)?;
+                zip.write_all(data).map_err(EngineError::archive)
+            }
+        }
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        if let Target::Zip(zip) = self.target {
+            zip.into_inner()
+                .unwrap()
+                .finish()
+                .context("Cannot finish ZIP archive")?;
+        }
+        Ok(())
+    }
+}
+
+// Expands files, directories (one level, image files only) and glob patterns
+fn collect_inputs(inputs: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)
+                .with_context(|| format!("Cannot read directory {}", input))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|p| p.is_file() && ImageFormat::from_path(p).is_ok())
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else if path.is_file() {
+            files.push(path.to_path_buf());
+        } else {
+            let matches: Vec<PathBuf> = glob::glob(input)
+                .with_context(|| format!("Invalid pattern {}", input))?
+                .filter_map(Result::ok)
+                .filter(|p| p.is_file())
+                .collect();
+            if matches.is_empty() {
+                bail!("No files match '{}'", input);
+            }
+            files.extend(matches);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    files.retain(|p| seen.insert(p.clone()));
+    Ok(files)
+}
+
+fn convert_file(
+    path: &Path,
+    settings: &ImageSettings,
+    upload_settings: &UploadSettings,
+    output: &Output,
+) -> Result<(String, ConvertedUpload), EngineError> {
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let data = fs::read(path).map_err(|e| EngineError::Decode {
+        filename: filename.clone(),
+        reason: e.to_string(),
+    })?;
+
+    let upload = convert_upload(&filename, &data, settings, upload_settings, || Ok(()))?;
+    let new_filename = output_filename(&filename, &upload.image.format);
+    output.write(&new_filename, &upload.image.data)?;
+    Ok((new_filename, upload))
+}
+
+fn run(args: Args) -> anyhow::Result<usize> {
+    // Same config file and env overrides as the server: limits and encoder defaults
+    let config = AppConfig::load().context("Invalid configuration")?;
+    let files = collect_inputs(&args.inputs)?;
+
+    // Inputs with the same name in different directories would overwrite each other. With
+    // auto the extension is only known after encoding, so `Output::write` catches those.
+    if args.format != AUTO_FORMAT {
+        let mut outputs = HashSet::new();
+        for path in &files {
+            let name = output_filename(&path.file_name().unwrap().to_string_lossy(), &args.format);
+            if !outputs.insert(name.clone()) {
+                bail!("More than one input would be written to '{}'", name);
+            }
+        }
+    }
+
+    let auto_orient = args.auto_orient(config.defaults.auto_orient);
+    let settings = ImageSettings {
+        transforms: args.transforms,
+        resolution: args.resize,
+        fit: match &args.fit {
+            Some(mode) => mode.parse()?,
+            None if args.keep_aspect => ResizeMode::Contain,
+            None => ResizeMode::Fill,
+        },
+        gravity: args.gravity.parse()?,
+        background: args.background,
+        quality: args
+            .quality
+            .or_else(|| config.default_quality(&args.format)),
+        max_bytes: args.max_bytes,
+        downscale_to_fit: args.downscale_to_fit,
+        target_ssim: args.target_ssim,
+        candidates: if args.format == AUTO_FORMAT {
+            config.format_candidates(
+                (!args.auto_formats.is_empty()).then_some(args.auto_formats.as_slice()),
+                args.quality,
+                args.compression,
+            )?
+        } else {
+            Vec::new()
+        },
+        compression: args
+            .compression
+            .or_else(|| config.default_compression(&args.format)),
+        bit_depth: args.bit_depth,
+        webp: WebpOptions {
+            lossless: args.lossless,
+            near_lossless: args.near_lossless,
+            alpha_quality: args.alpha_quality,
+            method: args.method,
+            exact: args.exact,
+        },
+        format: args.format,
+    };
+    settings.validate()?;
+    let upload_settings = UploadSettings {
+        auto_orient,
+        metadata: match &args.metadata {
+            Some(policy) => policy.parse()?,
+            None => config.defaults.metadata,
+        },
+        color_profile: match &args.color_profile {
+            Some(mode) => mode.parse()?,
+            None => config.defaults.color_profile,
+        },
+        limits: config.decode_limits(),
+    };
+    let threads = args
+        .jobs
+        .or(match config.engine.threads {
+            0 => None,
+            threads => Some(threads),
+        })
+        .unwrap_or_else(|| num_cpus::get_physical().max(2))
+        .clamp(1, files.len().max(1));
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .context("Cannot start worker pool")?;
+    let output = Output::create(&args.output)?;
+
+    println!(
+        "🔧 Converting {} file(s) to {} with {} worker(s)",
+        files.len(),
+        settings.format,
+        threads
+    );
+    let start = Instant::now();
+
+    let searched = settings.max_bytes.is_some() || settings.target_ssim.is_some();
+    let failed = pool.install(|| {
+        files
+            .par_iter()
+            .filter(|path| {
+                let file_start = Instant::now();
+                match convert_file(path, &settings, &upload_settings, &output) {
+                    Ok((new_filename, upload)) => {
+                        let profile = match upload.color_profile {
+                            ProfileAction::None => String::new(),
+                            action => format!(", ICC {:?}", action).to_lowercase(),
+                        };
+                        // Only interesting when it was searched for
+                        let mut quality = match upload.image.quality {
+                            Some(quality) if searched => format!(" at quality {}", quality),
+                            _ => String::new(),
+                        };
+                        if let Some(score) = upload.image.ssim {
+                            quality.push_str(&format!(", SSIM {:.4}", score));
+                        }
+                        println!(
+                            "✅ {} -> {} ({} bytes{}{}, {:.2?})",
+                            path.display(),
+                            new_filename,
+                            upload.image.data.len(),
+                            quality,
+                            profile,
+                            file_start.elapsed()
+                        );
+                        false
+                    }
+                    Err(e) => {
+                        eprintln!("❌ {}: {}", path.display(), e);
+                        true
+                    }
+                }
+            })
+            .count()
+    });
+
+    output.finish()?;
+    println!(
+        "📦 {} of {} file(s) converted into {} in {:.2?}",
+        files.len() - failed,
+        files.len(),
+        args.output.display(),
+        start.elapsed()
+    );
+    Ok(failed)
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+
+    match run(Args::parse()) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("❌ {:#}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(flags: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(["convertxion", "-f", "png", "in.jpg"].iter().chain(flags))
+    }
+
+    #[test]
+    fn orientation_flags_override_the_config_default() {
+        let args = parse(&[]).unwrap();
+        assert!(args.auto_orient(true));
+        assert!(!args.auto_orient(false));
+
+        let args = parse(&["--auto-orient"]).unwrap();
+        assert!(args.auto_orient(false));
+
+        let args = parse(&["--no-auto-orient"]).unwrap();
+        assert!(!args.auto_orient(true));
+    }
+
+    #[test]
+    fn resize_sizes_are_positive() {
+        assert_eq!(parse_resize("800x600"), Ok((800, 600)));
+        assert_eq!(parse_resize(" 800 , 600 "), Ok((800, 600)));
+        for value in ["0x10", "10x0", "x", "800", "99999999999x10", "-1x10"] {
+            assert!(parse_resize(value).is_err(), "accepted {:?}", value);
+        }
+    }
+
+    #[test]
+    fn orientation_flags_conflict() {
+        assert!(parse(&["--auto-orient", "--no-auto-orient"]).is_err());
+    }
+
+    #[test]
+    fn outputs_never_replace_each_other() {
+        let dir = std::env::temp_dir().join(format!("convertxion-test-{}", std::process::id()));
+        let output = Output::create(&dir).unwrap();
+        output.write("a.webp", b"first").unwrap();
+        let err = output.write("a.webp", b"second").unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+        assert_eq!(fs::read(dir.join("a.webp")).unwrap(), b"first");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bit_depth_is_limited_to_avif_depths() {
+        assert_eq!(parse(&["--bit-depth", "10"]).unwrap().bit_depth, Some(10));
+        for value in ["12", "7", "abc"] {
+            assert!(
+                parse(&["--bit-depth", value]).is_err(),
+                "accepted {}",
+                value
+            );
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::error::EngineError;
+use image::DynamicImage;
+use log::{debug, warn};
+use qcms::{DataType, Intent, Profile, Transform};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Offset of the data color space signature in the ICC header
+const ICC_COLOR_SPACE: std::ops::Range<usize> = 16..20;
+
+/// What to do with an upload's embedded ICC profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorProfileMode {
+    /// Convert the pixels to sRGB and write an untagged (sRGB) file.
+    #[default]
+    Srgb,
+    /// Keep the pixels and embed the original profile; formats that cannot carry a profile
+    /// (BMP) are converted to sRGB instead.
+    Embed,
+}
+
+impl FromStr for ColorProfileMode {
+    type Err = EngineError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "srgb" => Ok(ColorProfileMode::Srgb),
+            "embed" => Ok(ColorProfileMode::Embed),
+            other => Err(EngineError::InvalidRequest(format!(
+                "Unknown color profile mode '{}' (expected srgb or embed)",
+                other
+            ))),
+        }
+    }
+}
+
+/// What happened to a file's ICC profile, reported in its [`FileResult`](crate::image_utils::FileResult).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileAction {
+    /// The upload has no profile.
+    None,
+    /// The profile is sRGB already; nothing to convert.
+    Srgb,
+    /// Pixels were converted from the profile to sRGB.
+    Converted,
+    /// The original profile was embedded in the output.
+    Embedded,
+    /// The profile could not be used (unreadable, or not an RGB profile) and was dropped.
+    Ignored,
+}
+
+/// Applies `mode` to an image decoded from an upload carrying `icc`.
+/// Returns the image, what was done, and the profile to embed in the output, if any.
+pub fn apply_profile(
+    img: DynamicImage,
+    icc: Option<Vec<u8>>,
+    mode: ColorProfileMode,
+    format: &str,
+) -> (DynamicImage, ProfileAction, Option<Vec<u8>>) {
+    let Some(icc) = icc else {
+        return (img, ProfileAction::None, None);
+    };
+    if icc.get(ICC_COLOR_SPACE) != Some(b"RGB ") {
+        debug!("Ignoring ICC profile that is not an RGB profile");
+        return (img, ProfileAction::Ignored, None);
+    }
+    if mode == ColorProfileMode::Embed && format != "bmp" {
+        return (img, ProfileAction::Embedded, Some(icc));
+    }
+
+    match to_srgb(img, &icc) {
+        Ok((img, action)) => (img, action, None),
+        Err(img) => (img, ProfileAction::Ignored, None),
+    }
+}
+
+// Converts the pixels from `icc` to sRGB, handing the image back untouched when it cannot
+fn to_srgb(img: DynamicImage, icc: &[u8]) -> Result<(DynamicImage, ProfileAction), DynamicImage> {
+    let Some(profile) = Profile::new_from_slice(icc, false) else {
+        warn!("Cannot read the embedded ICC profile, leaving pixels as they are");
+        return Err(img);
+    };
+    if profile.is_sRGB() {
+        return Ok((img, ProfileAction::Srgb));
+    }
+
+    // qcms works on 8-bit pixels, so 16-bit and float images are reduced first
+    let alpha = img.color().has_alpha();
+    let data_type = if alpha {
+        DataType::RGBA8
+    } else {
+        DataType::RGB8
+    };
+    let Some(transform) = Transform::new(
+        &profile,
+        &Profile::new_sRGB(),
+        data_type,
+        Intent::Perceptual,
+    ) else {
+        warn!("Cannot build a transform from the embedded ICC profile to sRGB");
+        return Err(img);
+    };
+
+    let img = if alpha {
+        let mut rgba = img.into_rgba8();
+        transform.apply(&mut rgba);
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        let mut rgb = img.into_rgb8();
+        transform.apply(&mut rgb);
+        DynamicImage::ImageRgb8(rgb)
+    };
+    Ok((img, ProfileAction::Converted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    // Display P3 primaries with a gamma 2.2 curve
+    const DISPLAY_P3: &[u8] = include_bytes!("../testdata/display-p3.icc");
+
+    fn pixel_image(pixel: [u8; 3]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb(pixel)))
+    }
+
+    fn assert_close(actual: [u8; 3], expected: [u8; 3]) {
+        let close = actual
+            .iter()
+            .zip(expected)
+            .all(|(&a, e)| a.abs_diff(e) <= 2);
+        assert!(close, "{:?} is not close to {:?}", actual, expected);
+    }
+
+    #[test]
+    fn display_p3_pixels_are_converted_to_srgb() {
+        // Expected values from the profile's matrix and curve, through the sRGB matrix
+        for (p3, srgb) in [([200, 100, 50], [216, 92, 24]), ([128, 0, 0], [142, 0, 0])] {
+            let (img, action, icc) = apply_profile(
+                pixel_image(p3),
+                Some(DISPLAY_P3.to_vec()),
+                ColorProfileMode::Srgb,
+                "png",
+            );
+            assert_eq!(action, ProfileAction::Converted);
+            assert_eq!(icc, None);
+            assert_close(img.to_rgb8().get_pixel(1, 1).0, srgb);
+        }
+    }
+
+    #[test]
+    fn alpha_survives_the_conversion() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([200, 100, 50, 77])));
+        let (img, action, _) = apply_profile(
+            img,
+            Some(DISPLAY_P3.to_vec()),
+            ColorProfileMode::Srgb,
+            "webp",
+        );
+        assert_eq!(action, ProfileAction::Converted);
+        let pixel = img.to_rgba8().get_pixel(0, 0).0;
+        assert_close([pixel[0], pixel[1], pixel[2]], [216, 92, 24]);
+        assert_eq!(pixel[3], 77);
+    }
+
+    #[test]
+    fn sixteen_bit_images_are_reduced_to_eight_bits() {
+        let img = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            Rgb([200 * 257, 100 * 257, 50 * 257]),
+        ));
+        let (img, _, _) = apply_profile(
+            img,
+            Some(DISPLAY_P3.to_vec()),
+            ColorProfileMode::Srgb,
+            "png",
+        );
+        assert_eq!(img.color(), image::ColorType::Rgb8);
+        assert_close(img.to_rgb8().get_pixel(0, 0).0, [216, 92, 24]);
+    }
+
+    #[test]
+    fn embed_keeps_the_pixels_and_the_profile() {
+        let (img, action, icc) = apply_profile(
+            pixel_image([200, 100, 50]),
+            Some(DISPLAY_P3.to_vec()),
+            ColorProfileMode::Embed,
+            "jpg",
+        );
+        assert_eq!(action, ProfileAction::Embedded);
+        assert_eq!(icc.as_deref(), Some(DISPLAY_P3));
+        assert_eq!(img.to_rgb8().get_pixel(0, 0).0, [200, 100, 50]);
+
+        // BMP cannot carry a profile, so it is converted instead
+        let (_, action, icc) = apply_profile(
+            pixel_image([200, 100, 50]),
+            Some(DISPLAY_P3.to_vec()),
+            ColorProfileMode::Embed,
+            "bmp",
+        );
+        assert_eq!(action, ProfileAction::Converted);
+        assert_eq!(icc, None);
+    }
+
+    #[test]
+    fn missing_foreign_or_broken_profiles_leave_the_pixels() {
+        let (_, action, _) =
+            apply_profile(pixel_image([1, 2, 3]), None, ColorProfileMode::Srgb, "png");
+        assert_eq!(action, ProfileAction::None);
+
+        let mut cmyk = DISPLAY_P3.to_vec();
+        cmyk[ICC_COLOR_SPACE].copy_from_slice(b"CMYK");
+        let mut broken = DISPLAY_P3[..40].to_vec();
+        broken[0..4].copy_from_slice(&40u32.to_be_bytes());
+        for icc in [cmyk, broken] {
+            let (img, action, icc) = apply_profile(
+                pixel_image([1, 2, 3]),
+                Some(icc),
+                ColorProfileMode::Srgb,
+                "png",
+            );
+            assert_eq!(action, ProfileAction::Ignored);
+            assert_eq!(icc, None);
+            assert_eq!(img.to_rgb8().get_pixel(0, 0).0, [1, 2, 3]);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+#[cfg(feature = "server")]
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+#[cfg(feature = "server")]
+use serde::Serialize;
+use thiserror::Error;
+
+/// Every failure the conversion engine can report to a client.
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("Failed to decode '{filename}': {reason}")]
+    Decode { filename: String, reason: String },
+
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+
+    #[error("{0}")]
+    LimitExceeded(String),
+
+    #[error("Conversion canceled")]
+    Canceled,
+
+    #[error("Failed to encode {format}: {reason}")]
+    Encode { format: String, reason: String },
+
+    #[error("Failed to build archive: {0}")]
+    Archive(String),
+
+    #[error("{0}")]
+    InvalidRequest(String),
+
+    #[error("Unknown or expired session '{0}'")]
+    UnknownSession(String),
+
+    #[error("Too many active sessions (limit {0}), try again later")]
+    TooManySessions(usize),
+
+    #[error("No running conversion for session '{0}'")]
+    NoRunningConversion(String),
+
+    #[error("Job '{0}' not found or expired")]
+    JobNotFound(String),
+
+    #[error("Job '{0}' is still running")]
+    JobNotReady(String),
+
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl EngineError {
+    pub fn encode(format: &str, reason: impl ToString) -> Self {
+        EngineError::Encode {
+            format: format.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn archive(reason: impl ToString) -> Self {
+        EngineError::Archive(reason.to_string())
+    }
+
+    /// Stable, machine-readable identifier for the frontend.
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::Decode { .. } => "decode_failed",
+            EngineError::UnsupportedFormat(_) => "unsupported_format",
+            EngineError::LimitExceeded(_) => "limit_exceeded",
+            EngineError::Canceled => "canceled",
+            EngineError::Encode { .. } => "encode_failed",
+            EngineError::Archive(_) => "archive_failed",
+            EngineError::InvalidRequest(_) => "invalid_request",
+            EngineError::UnknownSession(_) => "unknown_session",
+            EngineError::TooManySessions(_) => "too_many_sessions",
+            EngineError::NoRunningConversion(_) => "no_running_conversion",
+            EngineError::JobNotFound(_) => "job_not_found",
+            EngineError::JobNotReady(_) => "job_not_ready",
+            EngineError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
+}
+
+#[cfg(feature = "server")]
+impl ResponseError for EngineError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            EngineError::Decode { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            EngineError::UnsupportedFormat(_) | EngineError::InvalidRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            EngineError::LimitExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            EngineError::Canceled | EngineError::JobNotReady(_) => StatusCode::CONFLICT,
+            EngineError::UnknownSession(_) => StatusCode::FORBIDDEN,
+            EngineError::JobNotFound(_) | EngineError::NoRunningConversion(_) => {
+                StatusCode::NOT_FOUND
+            }
+            EngineError::TooManySessions(_) => StatusCode::SERVICE_UNAVAILABLE,
+            EngineError::Encode { .. } | EngineError::Archive(_) | EngineError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_errors() -> Vec<EngineError> {
+        vec![
+            EngineError::Decode {
+                filename: "a.png".into(),
+                reason: "bad".into(),
+            },
+            EngineError::UnsupportedFormat("tga".into()),
+            EngineError::LimitExceeded("too big".into()),
+            EngineError::Canceled,
+            EngineError::encode("png", "boom"),
+            EngineError::archive("disk full"),
+            EngineError::InvalidRequest("no".into()),
+            EngineError::UnknownSession("s".into()),
+            EngineError::TooManySessions(10),
+            EngineError::NoRunningConversion("s".into()),
+            EngineError::JobNotFound("j".into()),
+            EngineError::JobNotReady("j".into()),
+            EngineError::Internal("oops".into()),
+        ]
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        let mut codes: Vec<&str> = all_errors().iter().map(|e| e.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), all_errors().len());
+    }
+
+    #[test]
+    fn messages_name_the_failure() {
+        assert_eq!(
+            EngineError::encode("webp", "bad config").to_string(),
+            "Failed to encode webp: bad config"
+        );
+        assert_eq!(
+            EngineError::Decode {
+                filename: "a.png".into(),
+                reason: "truncated".into()
+            }
+            .to_string(),
+            "Failed to decode 'a.png': truncated"
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn status_codes_follow_the_error_kind() {
+        let status = |e: EngineError| e.status_code().as_u16();
+        assert_eq!(status(EngineError::InvalidRequest("no".into())), 400);
+        assert_eq!(status(EngineError::UnsupportedFormat("tga".into())), 400);
+        assert_eq!(status(EngineError::UnknownSession("s".into())), 403);
+        assert_eq!(status(EngineError::JobNotFound("j".into())), 404);
+        assert_eq!(status(EngineError::NoRunningConversion("s".into())), 404);
+        assert_eq!(status(EngineError::Canceled), 409);
+        assert_eq!(status(EngineError::JobNotReady("j".into())), 409);
+        assert_eq!(status(EngineError::LimitExceeded("big".into())), 413);
+        let decode = EngineError::Decode {
+            filename: "a.png".into(),
+            reason: "bad".into(),
+        };
+        assert_eq!(status(decode), 422);
+        assert_eq!(status(EngineError::Internal("oops".into())), 500);
+        assert_eq!(status(EngineError::TooManySessions(10)), 503);
+    }
+
+    #[cfg(feature = "server")]
+    #[actix_web::test]
+    async fn error_response_is_code_and_message_json() {
+        let response = EngineError::LimitExceeded("too big".into()).error_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "code": "limit_exceeded", "message": "too big" })
+        );
+    }
+}
@@ -1,23 +1,55 @@
-use crate::image_utils::{process_images, ImageSettings};
-use crate::ws_handler::{ProgressChannels, ProgressMessage};
+use crate::app_config::{AppConfig, LimitsConfig, AUTO_FORMAT};
+use crate::color::ColorProfileMode;
+use crate::error::EngineError;
+use crate::image_utils::{process_images, CancelToken, ImageSettings, UploadSettings, WebpOptions};
+use crate::jobs::JobStore;
+use crate::metadata::MetadataPolicy;
+use crate::metrics::METRICS;
+use crate::progress::{ProgressReporter, Stage, PROGRESS_VALIDATED};
+use crate::resize::{self, Gravity, ResizeMode};
+use crate::scheduler::MemoryScheduler;
+use crate::sessions::SessionStore;
+use crate::ssim;
+use crate::transform::{self, Transform};
+use crate::ws_handler::{ProgressChannels, WsProgress};
 use actix_multipart::Multipart;
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{web, Error, HttpResponse, ResponseError};
+use bytes::Bytes;
 use futures::StreamExt;
-use log::debug;
+use image::Rgba;
+use log::{debug, info};
 use serde::de::{self, Visitor};
 use serde::Deserialize;
-use std::env;
+use std::collections::HashMap;
 use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use tokio::sync::Notify;
+use tokio::sync::mpsc;
 
 #[derive(Debug, Deserialize, Default)]
 pub struct ImageSettingsForm {
     #[serde(default)]
     pub resolution: Resolution,
     pub keep_aspect_ratio: bool,
+    #[serde(skip)]
+    pub transforms: Vec<Transform>,
+    pub gravity: Option<Gravity>,
+    #[serde(skip)]
+    pub background: Option<Rgba<u8>>,
     pub quality: Option<u8>,
+    pub max_bytes: Option<u64>,
+    pub downscale_to_fit: Option<bool>,
+    /// jpg and webp only; AVIF output cannot be measured, see `ssim::MEASURABLE_FORMATS`
+    pub target_ssim: Option<f64>,
+    pub auto_formats: Option<Vec<String>>,
     pub compression: Option<u8>,
+    pub bit_depth: Option<u8>,
+    #[serde(skip)]
+    pub webp: WebpOptions,
+    pub auto_orient: Option<bool>,
+    pub metadata: Option<MetadataPolicy>,
+    pub color_profile: Option<ColorProfileMode>,
     pub format: String,
 }
 
@@ -26,8 +58,44 @@ pub struct SessionQuery {
     pub session_id: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct JobQuery {
+    pub session_id: Option<String>,
+}
+
+/// `width,height` (or `widthxheight`), optionally followed by a resize mode: `800,600,cover`.
 #[derive(Debug, Default)]
-pub struct Resolution(pub Option<(u32, u32)>);
+pub struct Resolution {
+    pub size: Option<(u32, u32)>,
+    pub fit: Option<ResizeMode>,
+}
+
+impl FromStr for Resolution {
+    type Err = EngineError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            EngineError::InvalidRequest(format!(
+                "Invalid resolution '{}'. Expected 'width,height' or 'width,height,mode'",
+                value
+            ))
+        };
+        let parts: Vec<&str> = value.split([',', 'x']).map(str::trim).collect();
+        if !(2..=3).contains(&parts.len()) {
+            return Err(invalid());
+        }
+        let width = parts[0].parse::<u32>().map_err(|_| invalid())?;
+        let height = parts[1].parse::<u32>().map_err(|_| invalid())?;
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+        let fit = parts.get(2).map(|mode| mode.parse()).transpose()?;
+        Ok(Resolution {
+            size: Some((width, height)),
+            fit,
+        })
+    }
+}
 
 impl<'de> Deserialize<'de> for Resolution {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -39,23 +107,14 @@ impl<'de> Deserialize<'de> for Resolution {
             type Value = Resolution;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("a string in the format 'width,height'")
+                formatter.write_str("a string in the format 'width,height[,mode]'")
             }
 
             fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
             where
                 E: de::Error,
             {
-                let parts: Vec<&str> = value.split(',').collect();
-                if parts.len() == 2 {
-                    let width = parts[0].parse::<u32>().map_err(de::Error::custom)?;
-                    let height = parts[1].parse::<u32>().map_err(de::Error::custom)?;
-                    Ok(Resolution(Some((width, height))))
-                } else {
-                    Err(de::Error::custom(
-                        "Invalid resolution format. Expected 'width,height'",
-                    ))
-                }
+                value.parse().map_err(de::Error::custom)
             }
         }
 
@@ -65,30 +124,105 @@ impl<'de> Deserialize<'de> for Resolution {
 
 #[derive(Clone)]
 pub struct AppState {
-    pub cancel_flag: Arc<Mutex<bool>>,
-    pub cancel_notify: Arc<Notify>,
+    // One cancel token per running conversion, keyed by session_id
+    pub cancel_tokens: Arc<Mutex<HashMap<String, Arc<CancelToken>>>>,
+    // Memory budget shared by every conversion and job
+    pub memory: Arc<MemoryScheduler>,
+}
+
+impl AppState {
+    pub fn new(memory: MemoryScheduler) -> Self {
+        AppState {
+            cancel_tokens: Arc::default(),
+            memory: Arc::new(memory),
+        }
+    }
+
+    /// Registers a fresh cancel token for `session_id`, replacing any stale one.
+    pub fn register_session(&self, session_id: &str) -> Arc<CancelToken> {
+        let token = Arc::new(CancelToken::default());
+        self.cancel_tokens
+            .lock()
+            .unwrap()
+            .insert(session_id.to_string(), token.clone());
+        token
+    }
+
+    /// Cancels the whole conversion running for `session_id`.
+    /// Returns `false` when that session has nothing running.
+    pub fn cancel_session(&self, session_id: &str) -> bool {
+        match self.cancel_tokens.lock().unwrap().get(session_id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops one input file from the conversion running for `session_id`.
+    /// Returns `false` when that session has nothing running.
+    pub fn cancel_file(&self, session_id: &str, filename: &str) -> bool {
+        match self.cancel_tokens.lock().unwrap().get(session_id) {
+            Some(token) => {
+                token.cancel_file(filename);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the token for `session_id`, unless a newer request already replaced it.
+    pub fn release_session(&self, session_id: &str, token: &Arc<CancelToken>) {
+        let mut tokens = self.cancel_tokens.lock().unwrap();
+        if tokens
+            .get(session_id)
+            .is_some_and(|current| Arc::ptr_eq(current, token))
+        {
+            tokens.remove(session_id);
+        }
+    }
+}
+
+// Releases the session's cancel token however the request or job ends
+struct SessionGuard {
+    state: AppState,
+    session_id: String,
+    token: Arc<CancelToken>,
+}
+
+impl SessionGuard {
+    fn register(state: &AppState, session_id: &str) -> Self {
+        SessionGuard {
+            state: state.clone(),
+            session_id: session_id.to_string(),
+            token: state.register_session(session_id),
+        }
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.state.release_session(&self.session_id, &self.token);
+    }
 }
 
 // ✅ FILE VALIDATION FUNCTION (INCLUDED)
-pub async fn files_validation(files: &[(String, Vec<u8>)], format: &str) -> Result<(), Error> {
-    let max_files: usize = env::var("MAX_FILES")
-        .unwrap_or_else(|_| "3".to_string())
-        .parse()
-        .unwrap_or(3);
-    let max_file_size: usize = env::var("MAX_FILE_SIZE")
-        .unwrap_or_else(|_| "12".to_string())
-        .parse::<usize>()
-        .unwrap_or(12)
-        * 1024
-        * 1024;
-    let allowed_formats: Vec<String> = env::var("ALLOWED_FORMATS")
-        .unwrap_or_else(|_| "jpg,png,webp,avif,bmp".to_string())
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .collect();
+pub async fn files_validation(
+    files: &[(String, Vec<u8>)],
+    format: &str,
+    limits: &LimitsConfig,
+) -> Result<(), EngineError> {
+    let max_files = limits.max_files;
+    let max_file_size = limits.max_file_size();
+    let allowed_formats = &limits.allowed_formats;
+
+    if files.is_empty() {
+        return Err(EngineError::InvalidRequest("No files uploaded".to_string()));
+    }
 
     if files.len() > max_files {
-        return Err(actix_web::error::ErrorBadRequest(format!(
+        return Err(EngineError::LimitExceeded(format!(
             "Maximum {} files allowed. You uploaded {} files.",
             max_files,
             files.len()
@@ -97,7 +231,7 @@ pub async fn files_validation(files: &[(String, Vec<u8>)], format: &str) -> Resu
 
     for (filename, data) in files {
         if data.len() > max_file_size {
-            return Err(actix_web::error::ErrorBadRequest(format!(
+            return Err(EngineError::LimitExceeded(format!(
                 "File {} exceeds the {} MB size limit.",
                 filename,
                 max_file_size / 1024 / 1024
@@ -105,9 +239,10 @@ pub async fn files_validation(files: &[(String, Vec<u8>)], format: &str) -> Resu
         }
     }
 
-    if !allowed_formats.contains(&format.to_string()) {
-        return Err(actix_web::error::ErrorBadRequest(format!(
-            "Format '{}' is not allowed. Allowed formats: {}",
+    // format=auto is checked against its candidates instead
+    if format != AUTO_FORMAT && !allowed_formats.contains(&format.to_string()) {
+        return Err(EngineError::UnsupportedFormat(format!(
+            "'{}' is not allowed. Allowed formats: {}",
             format,
             allowed_formats.join(", ")
         )));
@@ -121,47 +256,246 @@ pub async fn health_check() -> HttpResponse {
     HttpResponse::Ok().body("Server is running!")
 }
 
+// ✅ METRICS ENDPOINT
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
+
+// ✅ SESSION ENDPOINT
+pub async fn create_session(sessions: web::Data<SessionStore>) -> Result<HttpResponse, Error> {
+    let session_id = sessions.issue()?;
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "session_id": session_id,
+        "expires_in_secs": sessions.ttl().as_secs(),
+    })))
+}
+
 // ✅ CANCEL ENDPOINT
-pub async fn cancel_conversion(state: web::Data<AppState>) -> HttpResponse {
-    *state.cancel_flag.lock().unwrap() = true;
-    state.cancel_notify.notify_one();
-    HttpResponse::Ok().body("Conversion canceled")
+pub async fn cancel_conversion(
+    web::Query(session_query): web::Query<SessionQuery>,
+    state: web::Data<AppState>,
+    sessions: web::Data<SessionStore>,
+) -> Result<HttpResponse, EngineError> {
+    sessions.validate(&session_query.session_id)?;
+
+    if state.cancel_session(&session_query.session_id) {
+        Ok(HttpResponse::Ok().body("Conversion canceled"))
+    } else {
+        Err(EngineError::NoRunningConversion(session_query.session_id))
+    }
 }
 
 // ✅ MAIN HANDLER FOR CONVERSION
 pub async fn convert_image(
-    mut payload: Multipart,
+    payload: Multipart,
     web::Query(session_query): web::Query<SessionQuery>,
     state: web::Data<AppState>,
+    sessions: web::Data<SessionStore>,
+    config: web::Data<AppConfig>,
     progress_channels: web::Data<ProgressChannels>,
 ) -> Result<HttpResponse, Error> {
+    sessions.validate(&session_query.session_id)?;
+
+    let session = SessionGuard::register(&state, &session_query.session_id);
+    let progress = WsProgress::reporter(&session_query.session_id, &progress_channels);
+
+    let (files, settings, upload) = read_upload(payload, &config, &session, &progress)
+        .await
+        .inspect_err(|e| progress.error(e))?;
+
+    // ✅ Process files, streaming the ZIP back while they finish
+    let (tx, mut rx) = mpsc::channel::<Result<Bytes, EngineError>>(STREAM_CHANNEL_SIZE);
+    let writer = ChannelWriter {
+        tx: tx.clone(),
+        buffer: Vec::new(),
+    };
+    actix_web::rt::spawn(async move {
+        let outcome = process_images(
+            files,
+            settings,
+            upload,
+            progress.clone(),
+            config.batch_options(state.memory.clone()),
+            session.token.clone(),
+            writer,
+        )
+        .await;
+        if let Err(e) = outcome {
+            debug!("Error processing images: {:?}", e);
+            progress.error(&e);
+            let _ = tx.send(Err(e)).await;
+        }
+        drop(session);
+    });
+
+    // Wait for the first chunk, so a batch that fails outright still gets a proper error status
+    match rx.recv().await {
+        Some(Ok(first_chunk)) => {
+            let rest = futures::stream::unfold(rx, |mut rx| async move {
+                rx.recv().await.map(|item| (item, rx))
+            });
+            Ok(HttpResponse::Ok()
+                .content_type("application/zip")
+                .streaming(futures::stream::once(async { Ok(first_chunk) }).chain(rest)))
+        }
+        Some(Err(e)) => Err(e.into()),
+        None => Err(EngineError::Internal("Conversion ended without output".to_string()).into()),
+    }
+}
+
+const STREAM_CHANNEL_SIZE: usize = 4;
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Blocking `Write` adapter that hands the ZIP to the HTTP response in chunks
+struct ChannelWriter {
+    tx: mpsc::Sender<Result<Bytes, EngineError>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send_buffer(&mut self) -> io::Result<()> {
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= STREAM_CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+}
+
+// ✅ ASYNC JOB API
+pub async fn create_job(
+    payload: Multipart,
+    web::Query(job_query): web::Query<JobQuery>,
+    state: web::Data<AppState>,
+    jobs: web::Data<JobStore>,
+    sessions: web::Data<SessionStore>,
+    config: web::Data<AppConfig>,
+    progress_channels: web::Data<ProgressChannels>,
+) -> Result<HttpResponse, Error> {
+    let job_id = uuid::Uuid::new_v4().to_string();
+    // Without an explicit session, the job gets a fresh one for progress and cancel
+    let session_id = match job_query.session_id {
+        Some(session_id) => {
+            sessions.validate(&session_id)?;
+            session_id
+        }
+        None => sessions.issue()?,
+    };
+
+    let session = SessionGuard::register(&state, &session_id);
+    let progress = WsProgress::reporter(&session_id, &progress_channels);
+
+    // The upload has to be read while the request is still open
+    let (files, settings, upload) = read_upload(payload, &config, &session, &progress)
+        .await
+        .inspect_err(|e| progress.error(e))?;
+
+    jobs.create(job_id.clone(), session_id.clone());
+    info!("📥 Job {} accepted ({} files)", job_id, files.len());
+
+    let jobs = jobs.get_ref().clone();
+    let task_job_id = job_id.clone();
+    // Run on the system arbiter: a worker's tasks are dropped when it stops, and jobs
+    // have to keep running through a graceful shutdown
+    actix_web::rt::System::current()
+        .arbiter()
+        .spawn(async move {
+            let outcome = process_images(
+                files,
+                settings,
+                upload,
+                progress.clone(),
+                config.batch_options(state.memory.clone()),
+                session.token.clone(),
+                Vec::new(),
+            )
+            .await;
+            if let Err(e) = &outcome {
+                debug!("Job {} failed: {:?}", task_job_id, e);
+                progress.error(e);
+            }
+            jobs.finish(&task_job_id, outcome);
+            drop(session);
+        });
+
+    Ok(HttpResponse::Accepted().json(serde_json::json!({
+        "job_id": job_id,
+        "session_id": session_id,
+    })))
+}
+
+pub async fn job_status(
+    job_id: web::Path<String>,
+    jobs: web::Data<JobStore>,
+) -> Result<HttpResponse, EngineError> {
+    let info = jobs
+        .info(&job_id)
+        .ok_or_else(|| EngineError::JobNotFound(job_id.to_string()))?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+pub async fn job_result(
+    job_id: web::Path<String>,
+    jobs: web::Data<JobStore>,
+) -> Result<HttpResponse, EngineError> {
+    jobs.with_result(&job_id, |result| match result {
+        Ok(zip_data) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.zip\"", job_id),
+            ))
+            .body(zip_data),
+        Err(e) => e.error_response(),
+    })
+}
+
+// Files, conversion settings and upload settings read from the multipart form
+type Upload = (Vec<(String, Vec<u8>)>, ImageSettings, UploadSettings);
+
+// Reads the multipart form into files and validated settings
+async fn read_upload(
+    mut payload: Multipart,
+    config: &AppConfig,
+    session: &SessionGuard,
+    progress: &ProgressReporter,
+) -> Result<Upload, EngineError> {
     let mut files = Vec::new();
     let mut image_settings: Option<ImageSettingsForm> = None;
 
-    // Send progress: 10% upload/validation start
-    if let Some(addr) = progress_channels
-        .lock()
-        .unwrap()
-        .get(&session_query.session_id)
-    {
-        addr.do_send(ProgressMessage(
-            "{\"progress\": 10.00% - Files uploading started}".to_string(),
-        ));
-    }
+    progress.stage(Stage::Uploading, 0.0, "Files uploading started");
 
     while let Some(item) = payload.next().await {
-        let mut field = item?;
+        let mut field = item.map_err(|e| EngineError::InvalidRequest(e.to_string()))?;
         let content_disposition = field.content_disposition().clone();
         let name = content_disposition.get_name().unwrap_or("");
         let mut data = Vec::new();
 
         while let Some(chunk) = field.next().await {
-            let chunk = chunk?;
+            let chunk = chunk.map_err(|e| EngineError::InvalidRequest(e.to_string()))?;
             data.extend_from_slice(&chunk[..]);
 
             // ✅ Check for cancel during upload
-            if *state.cancel_flag.lock().unwrap() {
-                return Err(actix_web::error::ErrorBadRequest("Conversion canceled"));
+            if session.token.is_canceled() {
+                return Err(EngineError::Canceled);
             }
         }
 
@@ -176,67 +510,366 @@ pub async fn convert_image(
             let form = image_settings.get_or_insert_with(Default::default);
             match name {
                 "format" => form.format = text,
+                "auto_formats" => {
+                    form.auto_formats =
+                        Some(text.split(',').map(|f| f.trim().to_string()).collect())
+                }
                 "quality" => form.quality = text.parse().ok(),
+                "max_bytes" => {
+                    form.max_bytes =
+                        Some(text.parse().ok().filter(|&b| b > 0).ok_or_else(|| {
+                            EngineError::InvalidRequest(format!("Invalid max_bytes '{}'", text))
+                        })?)
+                }
+                "downscale_to_fit" => form.downscale_to_fit = text.parse().ok(),
+                "target_ssim" => form.target_ssim = Some(ssim::parse_target(&text)?),
                 "compression" => form.compression = text.parse().ok(),
+                "bit_depth" => form.bit_depth = Some(parse_level(name, &text)?),
+                "lossless" => form.webp.lossless = text == "true",
+                "near_lossless" => form.webp.near_lossless = Some(parse_level(name, &text)?),
+                "alpha_quality" => form.webp.alpha_quality = Some(parse_level(name, &text)?),
+                "method" => form.webp.method = Some(parse_level(name, &text)?),
+                "exact" => form.webp.exact = text == "true",
                 "keep_aspect_ratio" => form.keep_aspect_ratio = text == "true",
-                "resolution" => {
-                    form.resolution =
-                        serde_json::from_str(&format!("\"{}\"", text)).unwrap_or_default()
-                }
+                "auto_orient" => form.auto_orient = text.parse().ok(),
+                "metadata" => form.metadata = Some(text.parse()?),
+                "color_profile" => form.color_profile = Some(text.parse()?),
+                "resolution" => form.resolution = text.parse()?,
+                "transforms" => form.transforms.extend(transform::parse_list(&text)?),
+                "gravity" => form.gravity = Some(text.parse()?),
+                "background" => form.background = Some(resize::parse_color(&text)?),
                 _ => {}
             }
         }
     }
 
-    // ✅ After validation complete
-    files_validation(&files, &image_settings.as_ref().unwrap().format).await?;
+    let image_settings = image_settings
+        .ok_or_else(|| EngineError::InvalidRequest("Image settings missing".to_string()))?;
 
-    if let Some(addr) = progress_channels
-        .lock()
-        .unwrap()
-        .get(&session_query.session_id)
-    {
-        addr.do_send(ProgressMessage(
-            "{\"progress\": 25.00% - Files Validated}".to_string(),
-        )); // 25% validated
-    }
+    // ✅ After validation complete
+    files_validation(&files, &image_settings.format, &config.limits).await?;
+    let candidates = if image_settings.format == AUTO_FORMAT {
+        config.format_candidates(
+            image_settings.auto_formats.as_deref(),
+            image_settings.quality,
+            image_settings.compression,
+        )?
+    } else {
+        Vec::new()
+    };
 
     // ✅ Prepare ImageSettings
-    let image_settings = image_settings
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("Image settings missing"))?;
     let settings = ImageSettings {
+        transforms: image_settings.transforms,
+        resolution: image_settings.resolution.size,
+        // keep_aspect_ratio is the older switch between contain and fill
+        fit: image_settings
+            .resolution
+            .fit
+            .unwrap_or(if image_settings.keep_aspect_ratio {
+                ResizeMode::Contain
+            } else {
+                ResizeMode::Fill
+            }),
+        gravity: image_settings.gravity.unwrap_or_default(),
+        background: image_settings
+            .background
+            .unwrap_or(resize::DEFAULT_BACKGROUND),
+        quality: image_settings
+            .quality
+            .or_else(|| config.default_quality(&image_settings.format)),
+        max_bytes: image_settings.max_bytes,
+        downscale_to_fit: image_settings.downscale_to_fit.unwrap_or(false),
+        target_ssim: image_settings.target_ssim,
+        candidates,
+        compression: image_settings
+            .compression
+            .or_else(|| config.default_compression(&image_settings.format)),
+        bit_depth: image_settings.bit_depth,
+        webp: image_settings.webp,
         format: image_settings.format,
-        resolution: image_settings.resolution.0,
-        keep_aspect_ratio: image_settings.keep_aspect_ratio,
-        quality: image_settings.quality,
-        compression: image_settings.compression,
     };
+    settings.validate()?;
+    progress.stage(Stage::Validated, PROGRESS_VALIDATED, "Files validated");
 
-    // ✅ Send ready to convert
-    if let Some(addr) = progress_channels
-        .lock()
-        .unwrap()
-        .get(&session_query.session_id)
-    {
-        addr.do_send(ProgressMessage(
-            "{\"progress\": 30.00% - Processing the files..}".to_string(),
-        )); // 30% ready to start
-    }
-
-    // ✅ Process files
-    let zip_data = process_images(
-        files,
-        settings,
-        session_query.session_id.clone(),
-        progress_channels.as_ref().clone(),
-    )
-    .await
-    .map_err(|e| {
-        debug!("Error processing images: {:?}", e);
-        actix_web::error::ErrorInternalServerError("Failed to process images")
-    })?;
-
-    Ok(HttpResponse::Ok()
-        .content_type("application/zip")
-        .body(zip_data))
+    let upload = UploadSettings {
+        auto_orient: image_settings
+            .auto_orient
+            .unwrap_or(config.defaults.auto_orient),
+        metadata: image_settings.metadata.unwrap_or(config.defaults.metadata),
+        color_profile: image_settings
+            .color_profile
+            .unwrap_or(config.defaults.color_profile),
+        limits: config.decode_limits(),
+    };
+
+    Ok((files, settings, upload))
+}
+
+// Numeric level from a form field; the ranges are checked by `ImageSettings::validate`
+fn parse_level(name: &str, text: &str) -> Result<u8, EngineError> {
+    text.trim()
+        .parse()
+        .map_err(|_| EngineError::InvalidRequest(format!("Invalid {} '{}'", name, text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test as actix_test, App};
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+    use std::time::Duration;
+
+    const BOUNDARY: &str = "convertxion-test-boundary";
+
+    fn state() -> AppState {
+        AppState::new(MemoryScheduler::new(1024))
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = io::Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut data, ImageOutputFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    // multipart/form-data body with one `file` per entry of `files`, then the text fields
+    fn multipart(files: &[(&str, Vec<u8>)], fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (filename, data) in files {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+                     Content-Type: application/octet-stream\r\n\r\n",
+                    BOUNDARY, filename
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    BOUNDARY, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    fn upload(uri: &str, body: Vec<u8>) -> actix_test::TestRequest {
+        actix_test::TestRequest::post()
+            .uri(uri)
+            .insert_header((
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            ))
+            .set_payload(body)
+    }
+
+    macro_rules! app {
+        () => {
+            actix_test::init_service(
+                App::new()
+                    .app_data(web::Data::new(state()))
+                    .app_data(web::Data::new(ProgressChannels::default()))
+                    .app_data(web::Data::new(JobStore::new(Duration::from_secs(60))))
+                    .app_data(web::Data::new(SessionStore::new(
+                        Duration::from_secs(60),
+                        10,
+                    )))
+                    .app_data(web::Data::new(AppConfig::default()))
+                    .configure(crate::routes::config),
+            )
+            .await
+        };
+    }
+
+    #[actix_web::test]
+    async fn job_is_created_polled_and_downloaded() {
+        let app = app!();
+        let body = multipart(&[("a.png", png(8, 8))], &[("format", "png")]);
+        let response = actix_test::call_service(&app, upload("/api/jobs", body).to_request()).await;
+        assert_eq!(response.status(), 202);
+        let created: serde_json::Value = actix_test::read_body_json(response).await;
+        let job_id = created["job_id"].as_str().unwrap().to_string();
+        assert!(created["session_id"].is_string());
+
+        let status = loop {
+            let request = actix_test::TestRequest::get()
+                .uri(&format!("/api/jobs/{}", job_id))
+                .to_request();
+            let status: serde_json::Value =
+                actix_test::call_and_read_body_json(&app, request).await;
+            if status["status"] != "running" {
+                break status;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(status["status"], "done");
+
+        let request = actix_test::TestRequest::get()
+            .uri(&format!("/api/jobs/{}/result", job_id))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("Content-Type").unwrap(),
+            "application/zip"
+        );
+        let zip = actix_test::read_body(response).await;
+        assert_eq!(status["result_size"], zip.len());
+        assert!(zip.starts_with(b"PK"));
+    }
+
+    #[actix_web::test]
+    async fn unknown_job_is_not_found() {
+        let app = app!();
+        for uri in ["/api/jobs/missing", "/api/jobs/missing/result"] {
+            let response = actix_test::call_service(
+                &app,
+                actix_test::TestRequest::get().uri(uri).to_request(),
+            )
+            .await;
+            assert_eq!(response.status(), 404);
+            let body: serde_json::Value = actix_test::read_body_json(response).await;
+            assert_eq!(body["code"], "job_not_found");
+        }
+    }
+
+    #[actix_web::test]
+    async fn cancel_without_a_running_conversion_is_a_json_error() {
+        let app = app!();
+        let request = actix_test::TestRequest::post()
+            .uri("/api/sessions")
+            .to_request();
+        let session: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        let session_id = session["session_id"].as_str().unwrap();
+
+        let request = actix_test::TestRequest::post()
+            .uri(&format!("/api/cancel?session_id={}", session_id))
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), 404);
+        let body: serde_json::Value = actix_test::read_body_json(response).await;
+        assert_eq!(body["code"], "no_running_conversion");
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_served_as_prometheus_text() {
+        let app = app!();
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::get().uri("/metrics").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        assert!(response
+            .headers()
+            .get("content-type")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = actix_test::read_body(response).await;
+        let text = std::str::from_utf8(&body).unwrap();
+        assert!(text.contains("# TYPE convertxion_websocket_sessions gauge"));
+    }
+
+    #[actix_web::test]
+    async fn invalid_settings_are_rejected_upfront() {
+        let app = app!();
+        for fields in [
+            [("format", "png"), ("max_bytes", "1000")],
+            [("format", "avif"), ("target_ssim", "0.95")],
+            [("format", "avif"), ("bit_depth", "abc")],
+            [("format", "avif"), ("bit_depth", "12")],
+            [("format", "png"), ("bit_depth", "10")],
+        ] {
+            let body = multipart(&[("a.png", png(8, 8))], &fields);
+            let response =
+                actix_test::call_service(&app, upload("/api/jobs", body).to_request()).await;
+            assert_eq!(response.status(), 400, "{:?}", fields);
+            let body: serde_json::Value = actix_test::read_body_json(response).await;
+            assert_eq!(body["code"], "invalid_request");
+        }
+    }
+
+    #[test]
+    fn resolution_takes_a_size_and_an_optional_mode() {
+        let resolution: Resolution = "800,600".parse().unwrap();
+        assert_eq!(resolution.size, Some((800, 600)));
+        assert_eq!(resolution.fit, None);
+
+        let resolution: Resolution = "800x600, cover".parse().unwrap();
+        assert_eq!(resolution.size, Some((800, 600)));
+        assert_eq!(resolution.fit, Some(ResizeMode::Cover));
+    }
+
+    #[test]
+    fn malformed_resolutions_are_rejected() {
+        for value in [
+            "0x10",
+            "10,0",
+            "x",
+            "",
+            "800",
+            "800,600,cover,extra",
+            "99999999999x10",
+            "-800,600",
+            "800,600,stretch",
+        ] {
+            let err = value.parse::<Resolution>().unwrap_err();
+            assert_eq!(err.code(), "invalid_request", "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn cancel_only_reaches_its_own_session() {
+        let state = state();
+        let first = state.register_session("first");
+        let second = state.register_session("second");
+
+        assert!(state.cancel_session("first"));
+        assert!(first.is_canceled());
+        assert!(!second.is_canceled());
+
+        assert!(state.cancel_file("second", "a.png"));
+        assert!(second.is_file_canceled("a.png"));
+        assert!(!second.is_canceled());
+
+        assert!(!state.cancel_session("unknown"));
+        assert!(!state.cancel_file("unknown", "a.png"));
+    }
+
+    #[test]
+    fn release_keeps_a_newer_token_for_the_session() {
+        let state = state();
+        let old = state.register_session("session");
+        let new = state.register_session("session");
+
+        state.release_session("session", &old);
+        assert!(state.cancel_session("session"));
+        assert!(new.is_canceled());
+        assert!(!old.is_canceled());
+
+        state.release_session("session", &new);
+        assert!(!state.cancel_session("session"));
+    }
+
+    #[test]
+    fn session_guard_releases_its_token_on_drop() {
+        let state = state();
+        let guard = SessionGuard::register(&state, "session");
+        assert!(state.cancel_session("session"));
+        assert!(guard.token.is_canceled());
+
+        drop(guard);
+        assert!(!state.cancel_session("session"));
+    }
 }
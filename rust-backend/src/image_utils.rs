@@ -1,74 +1,425 @@
-use crate::ws_handler::{MyWebSocket, ProgressMessage};
-use actix::Addr;
-use futures::future::join_all;
-use image::io::Reader as ImageReader;
-use image::{codecs::png::PngEncoder, DynamicImage, GenericImageView, ImageOutputFormat};
+use crate::app_config::AUTO_FORMAT;
+use crate::color::{self, ColorProfileMode, ProfileAction};
+use crate::error::EngineError;
+use crate::metadata::{self, Metadata, MetadataPolicy};
+use crate::metrics::METRICS;
+use crate::progress::{
+    FileProgress, ProgressReporter, Stage, PROGRESS_ARCHIVING, PROGRESS_DONE, PROGRESS_VALIDATED,
+};
+use crate::resize::{self, Gravity, ResizeMode};
+use crate::scheduler::{MemoryReservation, MemoryScheduler};
+use crate::ssim;
+use crate::transform::{self, Transform};
+use image::io::{Limits, Reader as ImageReader};
+use image::{
+    codecs::png::PngEncoder, DynamicImage, GenericImageView, ImageError, ImageOutputFormat, Rgba,
+};
 use log::error;
 use log::{debug, info};
 use mozjpeg::{ColorSpace, Compress};
-use num_cpus;
+use ravif::{BitDepth, Encoder as AvifEncoder, Img};
+use rgb::FromSlice;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::io::Cursor;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
-use tokio::sync::Semaphore;
-use webp::Encoder as WebpEncoder;
-use zip::write::FileOptions;
-use zip::write::ZipWriter;
+use tokio::sync::mpsc;
+use tokio::sync::{Notify, Semaphore};
+use webp::{Encoder as WebpEncoder, WebPConfig};
+use zip::write::{SimpleFileOptions, ZipWriter};
 
-const MAX_MEMORY_PER_FILE: usize = 50_108_864;
+const MANIFEST_FILENAME: &str = "manifest.json";
 
-pub type ProgressChannels = Arc<Mutex<std::collections::HashMap<String, Addr<MyWebSocket>>>>;
+/// Formats encoded at a quality setting, and so the ones `max_bytes` works with.
+pub const LOSSY_FORMATS: [&str; 3] = ["jpg", "webp", "avif"];
 
+// How often `downscale_to_fit` may shrink an image before giving up
+const MAX_DOWNSCALE_ROUNDS: usize = 8;
+
+// (input index, manifest entry, output name and bytes) sent from a worker to the archiver
+type FileOutcome = (usize, FileResult, Result<(String, Vec<u8>), EngineError>);
+
+/// How the pixels are converted. [`convert_image`] works on decoded pixels alone; the steps
+/// that need the original file are in [`UploadSettings`].
 #[derive(Clone)]
 pub struct ImageSettings {
     pub format: String,
+    pub transforms: Vec<Transform>, // Crop, rotate and flip steps, applied before resizing
     pub resolution: Option<(u32, u32)>,
-    pub keep_aspect_ratio: bool,
-    pub quality: Option<u8>,     // Make quality optional
-    pub compression: Option<u8>, // Make compression optional
+    pub fit: ResizeMode,          // How the image is fitted into `resolution`
+    pub gravity: Gravity,         // Anchor for the `cover` crop and the `pad` placement
+    pub background: Rgba<u8>,     // Letterbox color for `pad`
+    pub quality: Option<u8>,      // Make quality optional
+    pub max_bytes: Option<u64>,   // jpg/webp/avif: lower the quality until the output fits
+    pub downscale_to_fit: bool,   // Shrink the image when even quality 1 exceeds max_bytes
+    pub target_ssim: Option<f64>, // jpg/webp (not avif): lowest quality reaching this SSIM
+    pub candidates: Vec<FormatCandidate>, // Formats tried by `format=auto`
+    pub compression: Option<u8>,  // Make compression optional
+    pub bit_depth: Option<u8>,    // AVIF only: 8 or 10
+    pub webp: WebpOptions,        // Lossless, near-lossless and alpha controls
+}
+
+/// Steps that read the uploaded file rather than its pixels, honoured by [`convert_upload`]
+/// and [`process_images`] but not by [`convert_image`].
+#[derive(Debug, Clone)]
+pub struct UploadSettings {
+    pub auto_orient: bool, // Apply the EXIF Orientation tag before resizing
+    pub metadata: MetadataPolicy,
+    pub color_profile: ColorProfileMode,
+    pub limits: DecodeLimits,
+}
+
+// Both halves of a batch's settings, shared by its workers
+struct BatchSettings {
+    image: ImageSettings,
+    upload: UploadSettings,
+}
+
+/// A format tried by `format=auto`, with its own encoder settings.
+#[derive(Debug, Clone)]
+pub struct FormatCandidate {
+    pub format: String,
+    pub quality: Option<u8>,
+    pub compression: Option<u8>,
+}
+
+/// WebP encoder controls beyond the quality. The defaults encode lossy WebP as before.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebpOptions {
+    /// Encode losslessly; the quality then sets the compression effort, as in `cwebp`.
+    pub lossless: bool,
+    /// Near-lossless preprocessing, 0 (strongest) to 100 (off). Implies `lossless`.
+    pub near_lossless: Option<u8>,
+    /// Quality of the alpha plane in lossy mode, 0-100 (libwebp default: 100).
+    pub alpha_quality: Option<u8>,
+    /// Speed against size, 0 (fastest) to 6 (smallest) (libwebp default: 4).
+    pub method: Option<u8>,
+    /// Keep the color of fully transparent pixels instead of letting the encoder change it.
+    pub exact: bool,
+}
+
+impl WebpOptions {
+    /// Rejects levels outside the ranges libwebp accepts.
+    pub fn validate(&self) -> Result<(), EngineError> {
+        let check = |name: &str, value: Option<u8>, max: u8| match value {
+            Some(value) if value > max => Err(EngineError::InvalidRequest(format!(
+                "Invalid {} '{}' (expected 0-{})",
+                name, value, max
+            ))),
+            _ => Ok(()),
+        };
+        check("near_lossless", self.near_lossless, 100)?;
+        check("alpha_quality", self.alpha_quality, 100)?;
+        check("method", self.method, 6)
+    }
+
+    /// True when the output is encoded losslessly, with or without near-lossless preprocessing.
+    pub fn is_lossless(&self) -> bool {
+        self.lossless || self.near_lossless.is_some()
+    }
+
+    // Starts from what `Encoder::encode` uses, so the defaults keep their output
+    fn config(&self, quality: u8) -> Result<WebPConfig, EngineError> {
+        let mut config = WebPConfig::new()
+            .map_err(|_| EngineError::encode("webp", "cannot initialise the encoder config"))?;
+        let lossless = self.is_lossless();
+        config.lossless = lossless as i32;
+        config.alpha_compression = !lossless as i32;
+        config.quality = quality as f32;
+        if let Some(level) = self.near_lossless {
+            config.near_lossless = level as i32;
+        }
+        if let Some(alpha_quality) = self.alpha_quality {
+            config.alpha_quality = alpha_quality as i32;
+        }
+        if let Some(method) = self.method {
+            config.method = method as i32;
+        }
+        config.exact = self.exact as i32;
+        Ok(config)
+    }
+}
+
+impl ImageSettings {
+    /// Formats the output may end up in: the candidates for `format=auto`, else `format`.
+    pub fn output_formats(&self) -> Vec<&str> {
+        if self.format == AUTO_FORMAT {
+            self.candidates.iter().map(|c| c.format.as_str()).collect()
+        } else {
+            vec![self.format.as_str()]
+        }
+    }
+
+    /// Rejects settings no upload could be converted with, before any file is decoded:
+    /// WebP levels out of range, a bit depth other than 8 or 10 or without AVIF output,
+    /// `max_bytes` without an output whose quality can be searched, or `target_ssim` when
+    /// every output is a lossy format the engine cannot measure.
+    pub fn validate(&self) -> Result<(), EngineError> {
+        self.webp.validate()?;
+        let formats = self.output_formats();
+        if let Some(depth) = self.bit_depth {
+            if depth != 8 && depth != 10 {
+                return Err(EngineError::InvalidRequest(format!(
+                    "Invalid bit_depth '{}' (expected 8 or 10)",
+                    depth
+                )));
+            }
+            if !formats.contains(&"avif") {
+                return Err(EngineError::InvalidRequest(format!(
+                    "bit_depth only applies to avif output, not {}",
+                    formats.join(", ")
+                )));
+            }
+        }
+        if self.max_bytes.is_some() && !formats.iter().any(|f| self.quality_driven(f)) {
+            let formats: Vec<&str> = formats
+                .iter()
+                .map(|&f| match f {
+                    "webp" => "lossless webp",
+                    f => f,
+                })
+                .collect();
+            return Err(EngineError::InvalidRequest(format!(
+                "max_bytes needs a lossy jpg, webp or avif output, not {}",
+                formats.join(", ")
+            )));
+        }
+        if self.target_ssim.is_some() && !formats.iter().any(|f| ssim_available(f)) {
+            return Err(EngineError::InvalidRequest(format!(
+                "target_ssim is not available for {} output, which cannot be decoded to measure it",
+                formats.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    // True when the quality of `format` output trades fidelity for size, so it can be searched
+    fn quality_driven(&self, format: &str) -> bool {
+        LOSSY_FORMATS.contains(&format) && !(format == "webp" && self.webp.is_lossless())
+    }
+}
+
+/// Upper bounds checked against the image header before a file is decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_megapixels: f64,
+    /// Largest buffer (in bytes) the decoder may allocate for one image.
+    pub max_decode_memory: u64,
+}
+
+impl DecodeLimits {
+    /// Rejects dimensions read from the header that would be too costly to decode.
+    fn check(&self, filename: &str, (width, height): (u32, u32)) -> Result<(), EngineError> {
+        let megapixels = width as f64 * height as f64 / 1_000_000.0;
+        // Decoded size as RGBA8, which is what most conversions end up with
+        let decoded_bytes = width as u64 * height as u64 * 4;
+
+        let violation = if width > self.max_width || height > self.max_height {
+            Some(format!(
+                "dimensions exceed the {}x{} limit",
+                self.max_width, self.max_height
+            ))
+        } else if megapixels > self.max_megapixels {
+            Some(format!(
+                "{:.1} megapixels exceed the {} megapixel limit",
+                megapixels, self.max_megapixels
+            ))
+        } else if decoded_bytes > self.max_decode_memory {
+            Some(format!(
+                "decoding needs {} MB, more than the {} MB limit",
+                decoded_bytes / (1024 * 1024),
+                self.max_decode_memory / (1024 * 1024)
+            ))
+        } else {
+            None
+        };
+
+        match violation {
+            Some(reason) => Err(EngineError::LimitExceeded(format!(
+                "'{}' is {}x{}: {}",
+                filename, width, height, reason
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn decoder_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_decode_memory);
+        limits
+    }
+}
+
+/// How much of the machine one batch may use.
+#[derive(Clone)]
+pub struct BatchOptions {
+    /// Worker threads; 0 picks the physical core count (at least 2).
+    pub threads: usize,
+    /// Memory budget shared with every other batch in the process.
+    pub memory: Arc<MemoryScheduler>,
+}
+
+/// Cancellation state for one batch: either the whole batch or single input files.
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    canceled: AtomicBool,
+    files: Mutex<HashSet<String>>,
+    // Wakes tasks waiting in `file_canceled`
+    changed: Notify,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.canceled.store(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::SeqCst)
+    }
+
+    /// Drops `filename` (the uploaded name) from the batch.
+    pub fn cancel_file(&self, filename: &str) {
+        self.files.lock().unwrap().insert(filename.to_string());
+        self.changed.notify_waiters();
+    }
+
+    /// True when either the batch or this particular file was canceled.
+    pub fn is_file_canceled(&self, filename: &str) -> bool {
+        self.is_canceled() || self.files.lock().unwrap().contains(filename)
+    }
+
+    /// Resolves once the batch or `filename` is canceled.
+    pub async fn file_canceled(&self, filename: &str) {
+        loop {
+            // Registered before the check, so a cancel in between is not missed
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if self.is_file_canceled(filename) {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+/// Encoded output of [`convert_image`].
+pub struct ConvertedImage {
+    pub data: Vec<u8>,
+    pub dimensions: (u32, u32),
+    /// Output format; with `format=auto` the candidate that won.
+    pub format: String,
+    /// Encoder quality used, for the formats that take one.
+    pub quality: Option<u8>,
+    /// SSIM of the output against the resized image, when `target_ssim` was set.
+    pub ssim: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Ok,
+    Failed,
+    Canceled,
+}
+
+impl FileStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileStatus::Ok => "ok",
+            FileStatus::Failed => "failed",
+            FileStatus::Canceled => "canceled",
+        }
+    }
+}
+
+/// Per-file outcome, written to `manifest.json` and sent over the progress WebSocket.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileResult {
+    pub input: String,
+    pub output: Option<String>,
+    pub status: FileStatus,
+    pub error: Option<String>,
+    pub error_code: Option<&'static str>,
+    pub original_size: usize,
+    pub output_size: Option<usize>,
+    pub original_dimensions: Option<(u32, u32)>,
+    pub output_dimensions: Option<(u32, u32)>,
+    pub encode_time_ms: Option<u64>,
+    pub color_profile: Option<ProfileAction>,
+    pub quality: Option<u8>,
+    pub ssim: Option<f64>,
+}
+
+/// Contents of `manifest.json` in the output ZIP.
+#[derive(Debug, Serialize)]
+pub struct Manifest<'a> {
+    pub format: &'a str,
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub files: &'a [FileResult],
 }
 
+impl FileResult {
+    fn failed(input: String, original_size: usize, error: &EngineError) -> Self {
+        let status = match error {
+            EngineError::Canceled => FileStatus::Canceled,
+            _ => FileStatus::Failed,
+        };
+        FileResult {
+            input,
+            output: None,
+            status,
+            error: Some(error.to_string()),
+            error_code: Some(error.code()),
+            original_size,
+            output_size: None,
+            original_dimensions: None,
+            output_dimensions: None,
+            encode_time_ms: None,
+            color_profile: None,
+            quality: None,
+            ssim: None,
+        }
+    }
+}
+
+/// Transforms, resizes and encodes decoded pixels. Orientation, color profiles and metadata
+/// are left to [`convert_upload`].
 pub fn convert_image(
     img: DynamicImage,
     settings: &ImageSettings,
     filename: &str,
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send>> {
+) -> Result<ConvertedImage, EngineError> {
     debug!("Processing file: {}", filename);
 
     let start_time = Instant::now();
 
-    // Auto-fill missing quality or compression settings
-    let quality = settings.quality.unwrap_or(80); // Default quality to 80 if not provided
-    let compression = settings.compression.unwrap_or(8); // Default compression to 8 if not provided
-    let png_compression = settings.compression.unwrap_or(2);
-
     debug!("Original image dimensions: {:?}", img.dimensions());
 
+    let img = transform::apply(img, &settings.transforms, settings.background, filename)?;
+
     let img = if let Some((width, height)) = settings.resolution {
-        debug!("Resizing image to {}x{}", width, height);
-        let (orig_width, orig_height) = img.dimensions();
-        if width > orig_width || height > orig_height {
-            debug!(
-                "Upscaling image (original: {}x{}, target: {}x{})",
-                orig_width, orig_height, width, height
-            );
-            if settings.keep_aspect_ratio {
-                img.resize(width, height, image::imageops::FilterType::Lanczos3)
-            } else {
-                img.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
-            }
-        } else {
-            debug!(
-                "Downscaling image (original: {}x{}, target: {}x{})",
-                orig_width, orig_height, width, height
-            );
-            if settings.keep_aspect_ratio {
-                img.resize(width, height, image::imageops::FilterType::Lanczos3)
-            } else {
-                img.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
-            }
-        }
+        debug!(
+            "Resizing image to {}x{} ({:?})",
+            width, height, settings.fit
+        );
+        resize::resize(
+            img,
+            (width, height),
+            settings.fit,
+            settings.gravity,
+            settings.background,
+        )
     } else {
         debug!("No resizing required");
         img
@@ -76,6 +427,176 @@ pub fn convert_image(
 
     debug!("Resized image dimensions: {:?}", img.dimensions());
 
+    let encode_start = Instant::now();
+    let converted = if settings.format == AUTO_FORMAT {
+        encode_smallest(img, settings, filename)?
+    } else {
+        encode_image(img, settings, filename)?
+    };
+
+    // Labeled with the format that was written, which format=auto only knows now
+    let encode_time = encode_start.elapsed();
+    METRICS
+        .encode_seconds
+        .with_label_values(&[&converted.format])
+        .observe(encode_time.as_secs_f64());
+    debug!("Finished processing file: {}", filename);
+    debug!(
+        "Processing time for {}: {:?} (encoding {:?})",
+        filename,
+        start_time.elapsed(),
+        encode_time
+    );
+    Ok(converted)
+}
+
+// format=auto: encodes every suitable candidate and keeps the smallest output
+fn encode_smallest(
+    img: DynamicImage,
+    settings: &ImageSettings,
+    filename: &str,
+) -> Result<ConvertedImage, EngineError> {
+    let transparent = has_transparency(&img);
+    let mut best: Option<ConvertedImage> = None;
+    let mut last_error = None;
+
+    for candidate in &settings.candidates {
+        let format = candidate.format.as_str();
+        if format == "jpg" && transparent {
+            debug!("'{}' has transparency, skipping jpg", filename);
+            continue;
+        }
+        if settings.target_ssim.is_some() && !ssim_available(format) {
+            debug!("target_ssim cannot be measured on {}, skipping it", format);
+            continue;
+        }
+
+        let candidate_settings = ImageSettings {
+            format: candidate.format.clone(),
+            quality: candidate.quality,
+            compression: candidate.compression,
+            candidates: Vec::new(),
+            ..settings.clone()
+        };
+        match encode_image(img.clone(), &candidate_settings, filename) {
+            Ok(converted) => {
+                debug!(
+                    "'{}' as {}: {} bytes",
+                    filename,
+                    format,
+                    converted.data.len()
+                );
+                if best
+                    .as_ref()
+                    .is_none_or(|best| converted.data.len() < best.data.len())
+                {
+                    best = Some(converted);
+                }
+            }
+            Err(e) => {
+                debug!("'{}' as {} failed: {}", filename, format, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    best.ok_or_else(|| {
+        last_error.unwrap_or_else(|| {
+            EngineError::InvalidRequest(format!(
+                "None of the auto formats can be used for '{}'",
+                filename
+            ))
+        })
+    })
+}
+
+// True when the image has an alpha channel that is not fully opaque everywhere
+fn has_transparency(img: &DynamicImage) -> bool {
+    match img {
+        _ if !img.color().has_alpha() => false,
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().any(|p| p[3] < u8::MAX),
+        DynamicImage::ImageLumaA8(luma) => luma.pixels().any(|p| p[1] < u8::MAX),
+        _ => img.to_rgba16().pixels().any(|p| p[3] < u16::MAX),
+    }
+}
+
+// A similarity target can only be checked on formats the engine decodes again; lossless
+// output needs no check
+fn ssim_available(format: &str) -> bool {
+    !LOSSY_FORMATS.contains(&format) || ssim::MEASURABLE_FORMATS.contains(&format)
+}
+
+// True when the quality of the output format can be searched
+fn quality_driven(settings: &ImageSettings) -> bool {
+    settings.quality_driven(&settings.format)
+}
+
+// Encodes the resized image as `settings.format`, at the quality picked by the settings
+fn encode_image(
+    img: DynamicImage,
+    settings: &ImageSettings,
+    filename: &str,
+) -> Result<ConvertedImage, EngineError> {
+    // Auto-fill missing quality settings
+    let quality = settings.quality.unwrap_or(80); // Default quality to 80 if not provided
+
+    let lossy = quality_driven(settings);
+    // A similarity target picks the quality; max_bytes may still lower it afterwards
+    let (quality, searched) = match settings.target_ssim {
+        Some(target) if lossy => {
+            let (quality, data, score) = search_ssim(&img, settings, target, filename)?;
+            (quality, Some((data, score)))
+        }
+        _ => (quality, None),
+    };
+
+    let mut score = None;
+    let (img, data, quality) = match (settings.max_bytes, searched) {
+        (max_bytes, Some((data, ssim)))
+            if max_bytes.is_none_or(|max_bytes| data.len() as u64 <= max_bytes) =>
+        {
+            score = Some(ssim);
+            (img, data, quality)
+        }
+        (Some(max_bytes), _) => encode_within(img, settings, quality, max_bytes, filename)?,
+        (None, _) => {
+            let data = encode(&img, settings, quality)?;
+            (img, data, quality)
+        }
+    };
+
+    let ssim = match (settings.target_ssim, score) {
+        (None, _) => None,
+        // Lossless WebP may still change pixels (near-lossless, transparent areas), so measure it
+        (Some(_), _) if !lossy && settings.format == "webp" => {
+            Some(measure_ssim(&img, settings, &data)?)
+        }
+        // Lossless output is identical to its source
+        (Some(_), _) if !lossy => Some(1.0),
+        (Some(_), Some(score)) => Some(score),
+        // max_bytes lowered the quality or the size, so measure the final output
+        (Some(_), None) => Some(measure_ssim(&img, settings, &data)?),
+    };
+
+    Ok(ConvertedImage {
+        data,
+        dimensions: img.dimensions(),
+        format: settings.format.clone(),
+        quality: lossy.then_some(quality),
+        ssim,
+    })
+}
+
+// Encodes `img` as `settings.format` at `quality`
+fn encode(
+    img: &DynamicImage,
+    settings: &ImageSettings,
+    quality: u8,
+) -> Result<Vec<u8>, EngineError> {
+    // Auto-fill missing compression settings
+    let compression = settings.compression.unwrap_or(8); // Default compression to 8 if not provided
+    let png_compression = settings.compression.unwrap_or(2);
+
     let mut output = Cursor::new(Vec::new());
     match settings.format.as_str() {
         "png" => {
@@ -93,7 +614,7 @@ pub fn convert_image(
             ))
             .map_err(|e| {
                 debug!("Error converting to PNG: {}", e);
-                Box::new(e) as Box<dyn std::error::Error + Send>
+                EngineError::encode("png", e)
             })?;
         }
         "jpg" => {
@@ -147,34 +668,87 @@ pub fn convert_image(
             // Start compression
             let mut started = comp
                 .start_compress(Vec::new())
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+                .map_err(|e| EngineError::encode("jpg", e))?;
 
             started
                 .write_scanlines(rgb.as_raw())
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+                .map_err(|e| EngineError::encode("jpg", e))?;
 
             let jpeg_data = started
                 .finish()
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+                .map_err(|e| EngineError::encode("jpg", e))?;
 
             output.write_all(&jpeg_data).map_err(|e| {
                 debug!("Error writing JPG data: {}", e);
-                Box::new(e) as Box<dyn std::error::Error + Send>
+                EngineError::encode("jpg", e)
             })?;
         }
         "webp" => {
             debug!("Converting to WebP with quality: {}", quality);
-            let encoder = WebpEncoder::from_image(&img).map_err(|e| {
+            let encoder = WebpEncoder::from_image(img).map_err(|e| {
                 debug!("Error creating WebP encoder: {}", e);
-                Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                )) as Box<dyn std::error::Error + Send>
+                EngineError::encode("webp", e)
             })?;
-            let webp_data = encoder.encode(quality as f32);
+            let webp_data = encoder
+                .encode_advanced(&settings.webp.config(quality)?)
+                .map_err(|e| {
+                    debug!("Error converting to WebP: {:?}", e);
+                    EngineError::encode("webp", format!("{:?}", e))
+                })?;
             output.write_all(&webp_data).map_err(|e| {
                 debug!("Error writing WebP data: {}", e);
-                Box::new(e) as Box<dyn std::error::Error + Send>
+                EngineError::encode("webp", e)
+            })?;
+        }
+        "avif" => {
+            // ravif panics outside these ranges, so clamp user input first
+            let avif_quality = quality.clamp(1, 100) as f32;
+            let speed = compression.clamp(1, 10);
+            let depth = match settings.bit_depth {
+                Some(8) => BitDepth::Eight,
+                Some(10) => BitDepth::Ten,
+                None => BitDepth::Auto,
+                Some(other) => {
+                    let err_msg = format!("Unsupported AVIF bit depth: {}", other);
+                    debug!("{}", err_msg);
+                    return Err(EngineError::InvalidRequest(err_msg));
+                }
+            };
+            debug!(
+                "Converting to AVIF with quality: {}, speed: {} and depth: {:?}",
+                avif_quality, speed, depth
+            );
+
+            let encoder = AvifEncoder::new()
+                .with_quality(avif_quality)
+                .with_alpha_quality(avif_quality)
+                .with_speed(speed)
+                .with_bit_depth(depth);
+
+            let (width, height) = img.dimensions();
+            let encoded = if img.color().has_alpha() {
+                let rgba = img.to_rgba8();
+                encoder.encode_rgba(Img::new(
+                    rgba.as_raw().as_rgba(),
+                    width as usize,
+                    height as usize,
+                ))
+            } else {
+                let rgb = img.to_rgb8();
+                encoder.encode_rgb(Img::new(
+                    rgb.as_raw().as_rgb(),
+                    width as usize,
+                    height as usize,
+                ))
+            }
+            .map_err(|e| {
+                debug!("Error converting to AVIF: {}", e);
+                EngineError::encode("avif", e)
+            })?;
+
+            output.write_all(&encoded.avif_file).map_err(|e| {
+                debug!("Error writing AVIF data: {}", e);
+                EngineError::encode("avif", e)
             })?;
         }
         "bmp" => {
@@ -183,43 +757,186 @@ pub fn convert_image(
             img.write_to(&mut output, ImageOutputFormat::Bmp)
                 .map_err(|e| {
                     debug!("Error converting to BMP: {}", e);
-                    Box::new(e) as Box<dyn std::error::Error + Send>
+                    EngineError::encode("bmp", e)
                 })?;
         }
         _ => {
-            let err_msg = format!("Unsupported format: {}", settings.format);
-            debug!("{}", err_msg);
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                err_msg,
-            )) as Box<dyn std::error::Error + Send>);
+            debug!("Unsupported format: {}", settings.format);
+            return Err(EngineError::UnsupportedFormat(settings.format.clone()));
         }
     }
 
-    let elapsed_time = start_time.elapsed();
-    debug!("Finished processing file: {}", filename);
-    debug!("Encoding time for {}: {:?}", filename, elapsed_time);
     Ok(output.into_inner())
 }
 
-pub async fn process_images(
+// Highest quality up to `max_quality` whose output fits `max_bytes`; with downscale_to_fit
+// the image is shrunk when even quality 1 is too large
+fn encode_within(
+    mut img: DynamicImage,
+    settings: &ImageSettings,
+    max_quality: u8,
+    max_bytes: u64,
+    filename: &str,
+) -> Result<(DynamicImage, Vec<u8>, u8), EngineError> {
+    if !quality_driven(settings) {
+        return Err(EngineError::InvalidRequest(format!(
+            "max_bytes needs a lossy jpg, webp or avif output, not {}{}",
+            if settings.format == "webp" {
+                "lossless "
+            } else {
+                ""
+            },
+            settings.format
+        )));
+    }
+
+    for round in 0..=MAX_DOWNSCALE_ROUNDS {
+        let smallest = match search_quality(&img, settings, max_quality, max_bytes)? {
+            Ok((data, quality)) => {
+                debug!(
+                    "'{}' fits {} bytes at quality {} ({} bytes, {:?})",
+                    filename,
+                    max_bytes,
+                    quality,
+                    data.len(),
+                    img.dimensions()
+                );
+                return Ok((img, data, quality));
+            }
+            Err(smallest) => smallest,
+        };
+
+        let (width, height) = img.dimensions();
+        // Output size roughly follows the pixel count, so shrink the area by the overshoot
+        let factor = (max_bytes as f64 / smallest as f64).sqrt().clamp(0.5, 0.9);
+        let scaled = (
+            ((width as f64 * factor).round() as u32).max(1),
+            ((height as f64 * factor).round() as u32).max(1),
+        );
+        if !settings.downscale_to_fit || round == MAX_DOWNSCALE_ROUNDS || scaled == (width, height)
+        {
+            return Err(EngineError::LimitExceeded(format!(
+                "'{}' does not fit into {} bytes as {} (smallest output: {} bytes at quality 1, {}x{})",
+                filename, max_bytes, settings.format, smallest, width, height
+            )));
+        }
+        debug!(
+            "'{}' is {} bytes at quality 1, downscaling to {}x{}",
+            filename, smallest, scaled.0, scaled.1
+        );
+        img = img.resize_exact(scaled.0, scaled.1, image::imageops::FilterType::Lanczos3);
+    }
+    unreachable!("the last round returns")
+}
+
+// Lowest quality whose output reaches `target` SSIM, or quality 100 when none does
+fn search_ssim(
+    img: &DynamicImage,
+    settings: &ImageSettings,
+    target: f64,
+    filename: &str,
+) -> Result<(u8, Vec<u8>, f64), EngineError> {
+    if !ssim::MEASURABLE_FORMATS.contains(&settings.format.as_str()) {
+        return Err(EngineError::InvalidRequest(format!(
+            "target_ssim is not available for {} output, which cannot be decoded to measure it",
+            settings.format
+        )));
+    }
+
+    let mut best = None;
+    let mut closest = None;
+    let (mut low, mut high) = (1u8, 100u8);
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let data = encode(img, settings, quality)?;
+        let score = measure_ssim(img, settings, &data)?;
+        debug!("'{}' at quality {}: SSIM {:.5}", filename, quality, score);
+        if score >= target {
+            best = Some((quality, data, score));
+            high = quality - 1;
+        } else {
+            closest = Some((quality, data, score));
+            low = quality + 1;
+        }
+    }
+    // When nothing reaches the target, the last attempt was quality 100
+    Ok(best.or(closest).expect("at least one quality was tried"))
+}
+
+fn measure_ssim(
+    img: &DynamicImage,
+    settings: &ImageSettings,
+    data: &[u8],
+) -> Result<f64, EngineError> {
+    let decoded = ssim::decode_output(&settings.format, data)?;
+    Ok(ssim::ssim(img, &decoded))
+}
+
+// Binary search for the highest fitting quality; the error is the size at quality 1
+fn search_quality(
+    img: &DynamicImage,
+    settings: &ImageSettings,
+    max_quality: u8,
+    max_bytes: u64,
+) -> Result<Result<(Vec<u8>, u8), usize>, EngineError> {
+    let max_quality = max_quality.clamp(1, 100);
+    let data = encode(img, settings, max_quality)?;
+    if data.len() as u64 <= max_bytes {
+        return Ok(Ok((data, max_quality)));
+    }
+
+    let mut smallest = data.len();
+    let mut best = None;
+    let (mut low, mut high) = (1, max_quality - 1);
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let data = encode(img, settings, quality)?;
+        smallest = smallest.min(data.len());
+        if data.len() as u64 <= max_bytes {
+            best = Some((data, quality));
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(best.ok_or(smallest))
+}
+
+/// Converts a batch and streams the ZIP into `sink` as files finish, in completion order.
+/// Nothing is written until the first file succeeds, so a batch where every file fails
+/// returns its error without touching `sink`. An empty batch is an invalid request.
+pub async fn process_images<W: Write + Send + 'static>(
     files: Vec<(String, Vec<u8>)>,
     settings: ImageSettings,
-    session_id: String,
-    progress_channels: ProgressChannels,
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send>> {
+    upload: UploadSettings,
+    progress: ProgressReporter,
+    options: BatchOptions,
+    cancel: Arc<CancelToken>,
+    sink: W,
+) -> Result<W, EngineError> {
+    if files.is_empty() {
+        return Err(EngineError::InvalidRequest(
+            "No files to process".to_string(),
+        ));
+    }
     let total_files = files.len();
+    let format = settings.format.clone();
+    METRICS.jobs_started.with_label_values(&[&format]).inc();
 
-    let progress_per_file = 80.0 / total_files as f32;
+    let progress_per_file = (PROGRESS_ARCHIVING - PROGRESS_VALIDATED) / total_files as f32;
+    let completed = Arc::new(AtomicUsize::new(0));
     let start_total = Instant::now();
 
     // Calculate optimal thread count based on available cores and workload
     let desired_threads = std::cmp::min(
         total_files, // Don't exceed number of files
-        std::cmp::max(
-            2,                        // At least 2 threads
-            num_cpus::get_physical(), // Use physical core count instead of logical
-        ),
+        match options.threads {
+            0 => std::cmp::max(
+                2,                        // At least 2 threads
+                num_cpus::get_physical(), // Use physical core count instead of logical
+            ),
+            threads => threads,
+        },
     );
 
     info!("🔧 Using {} processing threads", desired_threads);
@@ -249,115 +966,473 @@ pub async fn process_images(
             .compression
             .map_or("Default".to_string(), |c| c.to_string())
     );
-    info!(
-        "📏 Keep Aspect Ratio: {}",
-        if settings.keep_aspect_ratio {
-            "Yes"
-        } else {
-            "No"
-        }
-    );
+    info!("📏 Fit: {:?} ({:?})", settings.fit, settings.gravity);
     info!("=========================================");
 
-    if let Some(addr) = progress_channels.lock().unwrap().get(&session_id) {
-        let _ = addr.try_send(ProgressMessage(
-            "{\"progress\": 10.00, \"filename\": \"Uploading started...\"}".to_string(),
-        ));
-    }
-
     info!("✅ Files successfully validated and ready to process...");
 
-    // Prepare output storage
-    let mut results: Vec<(String, Vec<u8>)> = Vec::new();
-    // Parallel processing using Tokio with memory limit
+    // Parallel processing using Tokio, within the global memory budget
     let semaphore = Arc::new(Semaphore::new(desired_threads));
-    // Use MAX_MEMORY_PER_FILE to calculate total memory limit
-    let memory_semaphore = Arc::new(Semaphore::new(MAX_MEMORY_PER_FILE * desired_threads));
-    let session_id = Arc::new(session_id);
-    let progress_channels = Arc::clone(&progress_channels);
-    let settings = Arc::new(settings);
+    let settings = Arc::new(BatchSettings {
+        image: settings,
+        upload,
+    });
+
+    // Finished files go straight to the archiver, so outputs are not held until the end
+    let (done_tx, done_rx) = mpsc::channel(desired_threads);
 
-    let tasks: Vec<_> = files
+    files
         .into_iter()
         .enumerate()
-        .map(|(index, (filename, data))| {
-            let permit = semaphore.clone().acquire_owned();
-            let mem_permit = memory_semaphore
-                .clone()
-                .acquire_many_owned(data.len() as u32);
-            let session_id = session_id.clone();
-            let progress_channels = progress_channels.clone();
+        .for_each(|(index, (filename, data))| {
+            let semaphore = semaphore.clone();
+            let memory = options.memory.clone();
+            let progress = progress.clone();
+            let completed = completed.clone();
             let settings = settings.clone();
+            let cancel = cancel.clone();
+            let done_tx = done_tx.clone();
+            let format = format.clone();
 
+            METRICS.queue_depth.inc();
             tokio::spawn(async move {
-                let _permit = permit.await;
-                let _mem_permit = mem_permit.await;
+                // Worker slot first, so only files about to run hold a share of the budget
+                let _permit = semaphore.acquire_owned().await;
+                let reservation =
+                    reserve_memory(&memory, &progress, &filename, &data, &settings, &cancel).await;
+                METRICS.queue_depth.dec();
+                let original_size = data.len();
+
                 let start_file = Instant::now();
+                let input_name = filename.clone();
+
+                let outcome = match reservation {
+                    Ok(reservation) => {
+                        let task_cancel = cancel.clone();
+                        let task_progress = progress.clone();
 
-                let result = tokio::task::spawn_blocking(move || {
-                    process_single_image(
-                        filename,
-                        data,
-                        &settings,
-                        &session_id,
-                        &progress_channels,
+                        tokio::task::spawn_blocking(move || {
+                            // Back to the budget once the file is converted
+                            let _reservation = reservation;
+                            process_single_image(
+                                filename,
+                                data,
+                                &settings,
+                                index,
+                                total_files,
+                                &task_cancel,
+                                &task_progress,
+                            )
+                        })
+                        .await
+                        .map_err(|e| EngineError::Internal(e.to_string()))
+                        .and_then(|res| res)
+                    }
+                    Err(e) => Err(e),
+                };
+
+                let (result, output) = match outcome {
+                    Ok((result, new_filename, converted_data)) => {
+                        let duration_file = start_file.elapsed();
+                        info!("⏱️ Processed '{}' in {:.2?}", new_filename, duration_file);
+                        (result, Ok((new_filename, converted_data)))
+                    }
+                    Err(e) => {
+                        error!("❌ Failed to process '{}': {}", input_name, e);
+                        (FileResult::failed(input_name, original_size, &e), Err(e))
+                    }
+                };
+
+                METRICS
+                    .files
+                    .with_label_values(&[&format, result.status.as_str()])
+                    .inc();
+
+                // Calculate progress from completed files, so it only moves forward
+                let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+                let percent = PROGRESS_VALIDATED + progress_per_file * done as f32;
+
+                // Log and WebSocket update
+                info!(
+                    "📦 [{}] Progress: {:.2}% | File: {} ({:?})",
+                    progress.id(),
+                    percent,
+                    result.input,
+                    result.status
+                );
+
+                progress.file(
+                    Stage::Encoding,
+                    Some(percent),
+                    FileProgress {
                         index,
-                        total_files,
-                        progress_per_file,
-                    )
-                })
-                .await
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)??;
+                        total: total_files,
+                        name: &result.input,
+                        result: Some(&result),
+                    },
+                );
 
-                // Memory permit is automatically released here when _mem_permit goes out of scope
-                let (new_filename, converted_data) = result;
-                let duration_file = start_file.elapsed();
-                info!("⏱️ Processed '{}' in {:.2?}", new_filename, duration_file);
+                let _ = done_tx.send((index, result, output)).await;
+            });
+        });
+    drop(done_tx);
 
-                Ok::<_, Box<dyn std::error::Error + Send>>((new_filename, converted_data))
-            })
-        })
-        .collect();
+    let archive_cancel = cancel.clone();
+    let archive_format = format.clone();
+    let outcome = tokio::task::spawn_blocking(move || {
+        write_archive(
+            done_rx,
+            sink,
+            &archive_format,
+            total_files,
+            &progress,
+            &archive_cancel,
+        )
+    })
+    .await
+    .map_err(|e| EngineError::Internal(e.to_string()))
+    .and_then(|res| res);
 
-    let results_temp = join_all(tasks).await;
-    for res in results_temp {
-        results.push(res.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)??);
+    match &outcome {
+        Ok(_) => {
+            METRICS.jobs_completed.with_label_values(&[&format]).inc();
+            info!(
+                "✅ All files processed and zipped in {:.2?}",
+                start_total.elapsed()
+            )
+        }
+        Err(_) => {
+            METRICS.jobs_failed.with_label_values(&[&format]).inc();
+            // Stop files still queued once the archive can no longer be delivered
+            cancel.cancel()
+        }
     }
 
-    // Final ZIP creation
-    let mut zip_buffer = Cursor::new(Vec::new());
-    let mut zip = ZipWriter::new(&mut zip_buffer);
+    outcome
+}
+
+// Writes finished files into a streaming ZIP as they arrive, then appends the manifest
+fn write_archive<W: Write>(
+    mut done_rx: mpsc::Receiver<FileOutcome>,
+    sink: W,
+    format: &str,
+    total_files: usize,
+    progress: &ProgressReporter,
+    cancel: &CancelToken,
+) -> Result<W, EngineError> {
+    let options = SimpleFileOptions::default();
+    let mut sink = Some(sink);
+    let mut zip = None;
+    // Keep every file's outcome; one bad input must not sink the whole batch
+    let mut manifest = Vec::with_capacity(total_files);
+    let mut first_error = None;
+
+    while let Some((index, result, output)) = done_rx.blocking_recv() {
+        if cancel.is_canceled() {
+            return Err(EngineError::Canceled);
+        }
+        manifest.push((index, result));
+        match output {
+            Ok((filename, data)) => {
+                let zip = zip.get_or_insert_with(|| {
+                    ZipWriter::new_stream(sink.take().expect("sink is only taken once"))
+                });
+                zip.start_file(filename, options)
+                    .map_err(EngineError::archive)?;
+                zip.write_all(&data).map_err(EngineError::archive)?;
+                // Push the finished entry out to the client right away
+                zip.flush().map_err(EngineError::archive)?;
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
 
-    for (filename, data) in results {
-        zip.start_file(filename, FileOptions::default())
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
-        zip.write_all(&data)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+    if cancel.is_canceled() {
+        return Err(EngineError::Canceled);
     }
 
-    zip.finish()
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
-    drop(zip);
+    // Nothing to deliver, so surface the first failure as the request error
+    let Some(mut zip) = zip else {
+        return Err(first_error
+            .unwrap_or_else(|| EngineError::InvalidRequest("No files to process".to_string())));
+    };
 
-    let total_duration = start_total.elapsed();
-    info!(
-        "✅ All files processed and zipped successfully in {:.2?}",
-        total_duration
+    manifest.sort_by_key(|(index, _)| *index);
+    let manifest: Vec<FileResult> = manifest.into_iter().map(|(_, result)| result).collect();
+    let failed = manifest
+        .iter()
+        .filter(|r| r.status != FileStatus::Ok)
+        .count();
+    let manifest_json = serde_json::to_vec_pretty(&Manifest {
+        format,
+        total: total_files,
+        succeeded: total_files - failed,
+        failed,
+        files: &manifest,
+    })
+    .map_err(EngineError::archive)?;
+
+    progress.stage(
+        Stage::Archiving,
+        PROGRESS_ARCHIVING,
+        "Finishing ZIP archive",
+    );
+
+    zip.start_file(MANIFEST_FILENAME, options)
+        .map_err(EngineError::archive)?;
+    zip.write_all(&manifest_json)
+        .map_err(EngineError::archive)?;
+
+    let mut sink = zip.finish().map_err(EngineError::archive)?.into_inner();
+    sink.flush().map_err(EngineError::archive)?;
+
+    progress.stage(
+        Stage::Done,
+        PROGRESS_DONE,
+        format!(
+            "{} of {} files converted",
+            total_files - failed,
+            total_files
+        ),
     );
 
-    Ok(zip_buffer.into_inner())
+    Ok(sink)
+}
+
+// Waits for the file's share of the memory budget, skipping files canceled while queued
+async fn reserve_memory(
+    memory: &Arc<MemoryScheduler>,
+    progress: &ProgressReporter,
+    filename: &str,
+    data: &[u8],
+    settings: &BatchSettings,
+    cancel: &CancelToken,
+) -> Result<MemoryReservation, EngineError> {
+    let canceled = || {
+        let canceled = cancel.is_file_canceled(filename);
+        if canceled {
+            info!("⏹️ Skipping '{}' (conversion canceled)", filename);
+        }
+        canceled
+    };
+    if canceled() {
+        return Err(EngineError::Canceled);
+    }
+
+    let bytes = estimate_memory(filename, data, &settings.image, &settings.upload)?;
+    debug!("Reserving {} bytes for '{}'", bytes, filename);
+    let reservation = memory
+        .reserve(progress.id(), filename, bytes, cancel)
+        .await?;
+
+    // The wait can be long, so check again before decoding
+    if canceled() {
+        return Err(EngineError::Canceled);
+    }
+    Ok(reservation)
+}
+
+/// Peak memory a conversion is expected to need, from the image headers alone: the upload,
+/// the decoded image and the oriented, resized or converted copy handed to the encoder,
+/// all as RGBA8.
+pub fn estimate_memory(
+    filename: &str,
+    data: &[u8],
+    settings: &ImageSettings,
+    upload: &UploadSettings,
+) -> Result<u64, EngineError> {
+    let (width, height) = probe_dimensions(filename, data)?;
+    upload.limits.check(filename, (width, height))?;
+
+    let decoded = width as u64 * height as u64 * 4;
+    let mut upright = (width, height);
+    let mut output = 0;
+    // Rotating or flipping copies the full decoded image before it is resized
+    if upload.auto_orient {
+        let orientation = read_orientation(data);
+        if orientation != 1 {
+            output = decoded;
+        }
+        if orientation >= 5 {
+            upright = (height, width);
+        }
+    }
+    let (transformed, transform_peak) = transform::planned_size(&settings.transforms, upright);
+    output = output.max(transform_peak * 4);
+    output = output.max(match settings.resolution {
+        Some(target) => resize::peak_pixels(transformed, target, settings.fit) * 4,
+        None => transformed.0 as u64 * transformed.1 as u64 * 4,
+    });
+    // Measuring SSIM decodes every attempt again, and format=auto encodes a copy per
+    // candidate, both next to the resized image
+    if settings.target_ssim.is_some() || settings.format == AUTO_FORMAT {
+        output *= 2;
+    }
+    Ok(data.len() as u64 + decoded + output)
+}
+
+// Reads the dimensions from the header without decoding any pixels
+fn probe_dimensions(filename: &str, data: &[u8]) -> Result<(u32, u32), EngineError> {
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| decode_error(filename, &e))?
+        .into_dimensions()
+        .map_err(|e| image_error(filename, e))
+}
+
+fn decode_error(filename: &str, e: &dyn std::fmt::Display) -> EngineError {
+    EngineError::Decode {
+        filename: filename.to_string(),
+        reason: e.to_string(),
+    }
+}
+
+fn image_error(filename: &str, e: ImageError) -> EngineError {
+    match e {
+        ImageError::Limits(e) => {
+            EngineError::LimitExceeded(format!("'{}' is too large to decode: {}", filename, e))
+        }
+        e => decode_error(filename, &e),
+    }
+}
+
+/// Decodes an uploaded file, rejecting it from its header alone when it exceeds `limits`.
+pub fn decode_image(
+    filename: &str,
+    data: &[u8],
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, EngineError> {
+    // Probe the header first, so oversized images are rejected before any pixel is allocated
+    limits.check(filename, probe_dimensions(filename, data)?)?;
+
+    // Decode image correctly
+    let decode_start = Instant::now();
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| decode_error(filename, &e))?;
+    decoder.limits(limits.decoder_limits());
+    let img = decoder.decode().map_err(|e| image_error(filename, e))?;
+    METRICS
+        .decode_seconds
+        .observe(decode_start.elapsed().as_secs_f64());
+    Ok(img)
+}
+
+/// EXIF Orientation (1-8) of a JPEG, PNG, WebP or TIFF upload; 1 when the tag is missing.
+pub fn read_orientation(data: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .filter(|orientation| (1..=8).contains(orientation))
+        .unwrap_or(1)
+}
+
+/// Turns `img` upright according to its EXIF `orientation`.
+/// The tag is not carried over to the output, so the pixels have to be rotated instead.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// An upload converted by [`convert_upload`].
+pub struct ConvertedUpload {
+    pub image: ConvertedImage,
+    pub original_dimensions: (u32, u32),
+    pub color_profile: ProfileAction,
+}
+
+/// Converts one uploaded file: decode, color profile, orientation, then [`convert_image`],
+/// with the kept metadata written into the output. `before_encode` runs once the image is
+/// decoded and can still abort the file.
+pub fn convert_upload(
+    filename: &str,
+    data: &[u8],
+    settings: &ImageSettings,
+    upload: &UploadSettings,
+    before_encode: impl FnOnce() -> Result<(), EngineError>,
+) -> Result<ConvertedUpload, EngineError> {
+    let img = decode_image(filename, data, &upload.limits)?;
+    let original_dimensions = img.dimensions();
+
+    let mut metadata = Metadata::read(data, upload.metadata);
+    let (mut img, color_profile, icc) = color::apply_profile(
+        img,
+        metadata::read_icc_profile(data),
+        upload.color_profile,
+        &settings.format,
+    );
+    metadata.icc = icc;
+    if upload.auto_orient {
+        img = apply_orientation(img, read_orientation(data));
+        metadata.mark_upright();
+    }
+
+    before_encode()?;
+
+    // The metadata is added after encoding, so it has to fit into max_bytes as well
+    let reserved = settings
+        .output_formats()
+        .into_iter()
+        .map(|format| metadata.embedded_size(format))
+        .max()
+        .unwrap_or(0) as u64;
+    let mut image = match settings.max_bytes {
+        Some(max_bytes) if reserved > 0 => {
+            let settings = ImageSettings {
+                max_bytes: Some(max_bytes.saturating_sub(reserved)),
+                ..settings.clone()
+            };
+            convert_image(img, &settings, filename)?
+        }
+        _ => convert_image(img, settings, filename)?,
+    };
+    image.data = metadata.embed(&image.format, image.data, image.dimensions)?;
+    Ok(ConvertedUpload {
+        image,
+        original_dimensions,
+        color_profile,
+    })
+}
+
+/// Name of the converted file: the input's image extension swapped for `format`.
+pub fn output_filename(filename: &str, format: &str) -> String {
+    format!(
+        "{}.{}",
+        filename
+            .trim_end_matches(".ppm")
+            .trim_end_matches(".png")
+            .trim_end_matches(".jpg")
+            .trim_end_matches(".jpeg")
+            .trim_end_matches(".webp")
+            .trim_end_matches(".avif")
+            .trim_end_matches(".bmp"),
+        format
+    )
 }
 
 fn process_single_image(
     filename: String,
     data: Vec<u8>,
-    settings: &ImageSettings,
-    session_id: &str,
-    progress_channels: &ProgressChannels,
+    settings: &BatchSettings,
     index: usize,
     total_files: usize,
-    progress_per_file: f32,
-) -> Result<(String, Vec<u8>), Box<dyn std::error::Error + Send>> {
+    cancel: &CancelToken,
+    progress: &ProgressReporter,
+) -> Result<(FileResult, String, Vec<u8>), EngineError> {
     info!(
         "🚀 [Thread: {:?}] [{} / {}] Starting processing for file: {}",
         std::thread::current().id(), // Add thread ID to see concurrent processing
@@ -366,52 +1441,836 @@ fn process_single_image(
         filename
     );
 
-    // Decode image correctly
-    let img = if filename.ends_with(".ppm") {
-        // Decode PPM manually
-        ImageReader::new(Cursor::new(&data))
-            .with_guessed_format()
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?
-            .decode()
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?
-    } else {
-        image::load_from_memory(&data)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?
+    let file_progress = || FileProgress {
+        index,
+        total: total_files,
+        name: &filename,
+        result: None,
     };
+    progress.file(Stage::Decoding, None, file_progress());
 
-    // Convert image
-    let converted_data = convert_image(img, settings, &filename)?;
+    let mut encode_start = Instant::now();
+    let upload = convert_upload(&filename, &data, &settings.image, &settings.upload, || {
+        // Decoding can take a while, so check again before encoding
+        if cancel.is_file_canceled(&filename) {
+            info!("⏹️ Canceled before encoding: {}", filename);
+            return Err(EngineError::Canceled);
+        }
+        progress.file(Stage::Encoding, None, file_progress());
+        encode_start = Instant::now();
+        Ok(())
+    })?;
+    let encode_time = encode_start.elapsed();
+    let converted = upload.image;
 
     // Prepare new filename
-    let new_filename = format!(
-        "{}.{}",
-        filename
-            .trim_end_matches(".ppm")
-            .trim_end_matches(".png")
-            .trim_end_matches(".jpg")
-            .trim_end_matches(".jpeg")
-            .trim_end_matches(".webp")
-            .trim_end_matches(".bmp"),
-        settings.format
-    );
+    let new_filename = output_filename(&filename, &converted.format);
 
-    // Calculate progress
-    let progress = 10.0 + (progress_per_file * (index as f32 + 1.0));
+    let result = FileResult {
+        input: filename,
+        output: Some(new_filename.clone()),
+        status: FileStatus::Ok,
+        error: None,
+        error_code: None,
+        original_size: data.len(),
+        output_size: Some(converted.data.len()),
+        original_dimensions: Some(upload.original_dimensions),
+        output_dimensions: Some(converted.dimensions),
+        encode_time_ms: Some(encode_time.as_millis() as u64),
+        color_profile: Some(upload.color_profile),
+        quality: converted.quality,
+        ssim: converted.ssim,
+    };
 
-    // Log and WebSocket update
-    info!(
-        "📦 [{}] Progress: {:.2}% | File: {}",
-        session_id, progress, new_filename
-    );
+    let format = [converted.format.as_str()];
+    METRICS
+        .input_bytes
+        .with_label_values(&format)
+        .inc_by(data.len() as u64);
+    METRICS
+        .output_bytes
+        .with_label_values(&format)
+        .inc_by(converted.data.len() as u64);
+    if !data.is_empty() {
+        METRICS
+            .compression_ratio
+            .with_label_values(&format)
+            .observe(converted.data.len() as f64 / data.len() as f64);
+    }
 
-    if let Some(addr) = progress_channels.lock().unwrap().get(session_id) {
-        let _ = addr.try_send(ProgressMessage(format!(
-            "{{\"progress\": {:.2}, \"filename\": \"{}\"}}",
-            progress, new_filename
-        )));
-    } else {
-        error!("❌ No WebSocket client for session_id: {}", session_id);
+    Ok((result, new_filename, converted.data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, RgbaImage};
+
+    fn settings(format: &str) -> ImageSettings {
+        ImageSettings {
+            format: format.to_string(),
+            transforms: Vec::new(),
+            resolution: None,
+            fit: ResizeMode::default(),
+            gravity: Gravity::default(),
+            background: resize::DEFAULT_BACKGROUND,
+            quality: None,
+            max_bytes: None,
+            downscale_to_fit: false,
+            target_ssim: None,
+            candidates: Vec::new(),
+            compression: None,
+            bit_depth: None,
+            webp: WebpOptions::default(),
+        }
+    }
+
+    fn upload_settings() -> UploadSettings {
+        UploadSettings {
+            auto_orient: true,
+            metadata: MetadataPolicy::Strip,
+            color_profile: ColorProfileMode::Srgb,
+            limits: DecodeLimits {
+                max_width: 4096,
+                max_height: 4096,
+                max_megapixels: 16.0,
+                max_decode_memory: 64 * 1024 * 1024,
+            },
+        }
+    }
+
+    // A JPEG with an EXIF APP1 segment holding only the Orientation tag
+    fn jpeg_with_orientation(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        gradient(width, height)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        // Big-endian TIFF header, then IFD0 with one SHORT entry and no next IFD
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&exif);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    // A smooth gradient with some texture, so quality settings change the output size
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let noise = ((x * 7 + y * 13) % 17) as u8 * 4;
+            Rgb([
+                (x * 255 / width) as u8,
+                (y * 255 / height) as u8,
+                128u8.wrapping_add(noise),
+            ])
+        }))
+    }
+
+    #[test]
+    fn cancel_token_cancels_single_files_or_the_batch() {
+        let token = CancelToken::default();
+        token.cancel_file("a.png");
+        assert!(token.is_file_canceled("a.png"));
+        assert!(!token.is_file_canceled("b.png"));
+        assert!(!token.is_canceled());
+
+        token.cancel();
+        assert!(token.is_canceled());
+        assert!(token.is_file_canceled("b.png"));
+    }
+
+    fn ok_outcome(index: usize, name: &str) -> FileOutcome {
+        let result = FileResult {
+            input: name.to_string(),
+            output: Some(output_filename(name, "png")),
+            status: FileStatus::Ok,
+            error: None,
+            error_code: None,
+            original_size: 10,
+            output_size: Some(3),
+            original_dimensions: Some((1, 1)),
+            output_dimensions: Some((1, 1)),
+            encode_time_ms: Some(0),
+            color_profile: Some(ProfileAction::None),
+            quality: None,
+            ssim: None,
+        };
+        (
+            index,
+            result,
+            Ok((output_filename(name, "png"), vec![1, 2, 3])),
+        )
     }
 
-    Ok((new_filename, converted_data))
+    fn failed_outcome(index: usize, name: &str, error: EngineError) -> FileOutcome {
+        let result = FileResult::failed(name.to_string(), 10, &error);
+        (index, result, Err(error))
+    }
+
+    // Feeds `outcomes` to the archiver, as the workers of a batch would
+    fn archive(outcomes: Vec<FileOutcome>) -> Result<Vec<u8>, EngineError> {
+        let total = outcomes.len();
+        let (tx, rx) = mpsc::channel(total.max(1));
+        for outcome in outcomes {
+            tx.try_send(outcome).unwrap();
+        }
+        drop(tx);
+        write_archive(
+            rx,
+            Vec::new(),
+            "png",
+            total,
+            &ProgressReporter::disabled("test"),
+            &CancelToken::default(),
+        )
+    }
+
+    fn read_zip(data: Vec<u8>) -> (Vec<String>, serde_json::Value) {
+        let mut zip = zip::ZipArchive::new(Cursor::new(data)).unwrap();
+        let names = zip.file_names().map(str::to_string).collect();
+        let manifest = zip.by_name(MANIFEST_FILENAME).unwrap();
+        (names, serde_json::from_reader(manifest).unwrap())
+    }
+
+    #[test]
+    fn failed_result_records_the_error() {
+        let failed = FileResult::failed("a.png".into(), 5, &EngineError::encode("png", "boom"));
+        assert_eq!(failed.status, FileStatus::Failed);
+        assert_eq!(failed.error_code, Some("encode_failed"));
+        assert_eq!(failed.output, None);
+
+        let canceled = FileResult::failed("a.png".into(), 5, &EngineError::Canceled);
+        assert_eq!(canceled.status, FileStatus::Canceled);
+    }
+
+    #[test]
+    fn partial_batch_keeps_good_files_and_lists_every_input() {
+        // Completion order differs from upload order
+        let zip = archive(vec![
+            ok_outcome(2, "c.png"),
+            failed_outcome(1, "b.png", EngineError::encode("png", "boom")),
+            ok_outcome(0, "a.png"),
+        ])
+        .unwrap();
+
+        let (names, manifest) = read_zip(zip);
+        assert_eq!(names, ["c.png", "a.png", MANIFEST_FILENAME]);
+        assert_eq!(manifest["total"], 3);
+        assert_eq!(manifest["succeeded"], 2);
+        assert_eq!(manifest["failed"], 1);
+        let inputs: Vec<&str> = manifest["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["input"].as_str().unwrap())
+            .collect();
+        assert_eq!(inputs, ["a.png", "b.png", "c.png"]);
+        assert_eq!(manifest["files"][1]["status"], "failed");
+        assert_eq!(manifest["files"][1]["error_code"], "encode_failed");
+    }
+
+    #[test]
+    fn batch_without_any_output_returns_the_first_error() {
+        let err = archive(vec![
+            failed_outcome(0, "a.png", EngineError::LimitExceeded("too big".into())),
+            failed_outcome(1, "b.png", EngineError::encode("png", "boom")),
+        ])
+        .unwrap_err();
+        assert_eq!(err.code(), "limit_exceeded");
+    }
+
+    fn png_file(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        gradient(width, height)
+            .write_to(&mut data, ImageOutputFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    async fn run_batch(
+        files: Vec<(String, Vec<u8>)>,
+        settings: ImageSettings,
+    ) -> Result<Vec<u8>, EngineError> {
+        let options = BatchOptions {
+            threads: 2,
+            memory: Arc::new(MemoryScheduler::new(64 * 1024 * 1024)),
+        };
+        process_images(
+            files,
+            settings,
+            upload_settings(),
+            ProgressReporter::disabled("test"),
+            options,
+            Arc::new(CancelToken::default()),
+            Vec::new(),
+        )
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_batch_is_an_invalid_request() {
+        let err = run_batch(Vec::new(), settings("png")).await.unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mixed_batch_streams_good_files_and_lists_the_bad_one() {
+        let files = vec![
+            ("a.png".to_string(), png_file(8, 6)),
+            ("broken.png".to_string(), b"not an image".to_vec()),
+            ("c.png".to_string(), png_file(4, 4)),
+        ];
+        let zip = run_batch(files, settings("png")).await.unwrap();
+
+        let (mut names, manifest) = read_zip(zip);
+        names.sort();
+        assert_eq!(names, ["a.png", "c.png", MANIFEST_FILENAME]);
+        assert_eq!(manifest["succeeded"], 2);
+        assert_eq!(manifest["failed"], 1);
+        let files = &manifest["files"];
+        assert_eq!(files[0]["output_dimensions"], serde_json::json!([8, 6]));
+        assert_eq!(files[1]["input"], "broken.png");
+        assert_eq!(files[1]["status"], "failed");
+        assert_eq!(files[1]["error_code"], "decode_failed");
+        assert_eq!(files[2]["status"], "ok");
+    }
+
+    #[test]
+    fn limits_check_dimensions_pixels_and_memory() {
+        let limits = DecodeLimits {
+            max_width: 1000,
+            max_height: 800,
+            max_megapixels: 0.5,
+            max_decode_memory: 1024 * 1024,
+        };
+        assert!(limits.check("ok.png", (500, 500)).is_ok());
+        for size in [(1001, 10), (10, 801), (800, 700)] {
+            let err = limits.check("big.png", size).unwrap_err();
+            assert_eq!(err.code(), "limit_exceeded", "{:?}", size);
+        }
+        // 0.36 MP fits the pixel limit, but not 1 MB as RGBA
+        let err = limits.check("wide.png", (600, 600)).unwrap_err();
+        assert!(err.to_string().contains("MB"), "{}", err);
+    }
+
+    #[test]
+    fn oversized_header_is_rejected_before_decoding() {
+        // A tiny PNG whose header claims 100000x100000 pixels
+        let mut data = png_file(8, 8);
+        data[16..20].copy_from_slice(&100_000u32.to_be_bytes());
+        data[20..24].copy_from_slice(&100_000u32.to_be_bytes());
+        let crc = crc32fast::hash(&data[12..29]);
+        data[29..33].copy_from_slice(&crc.to_be_bytes());
+
+        let limits = upload_settings().limits;
+        let err = decode_image("bomb.png", &data, &limits).unwrap_err();
+        assert_eq!(err.code(), "limit_exceeded");
+        assert!(decode_image("ok.png", &png_file(8, 8), &limits).is_ok());
+    }
+
+    #[test]
+    fn avif_output_is_an_avif_file() {
+        let settings = ImageSettings {
+            compression: Some(10),
+            ..settings("avif")
+        };
+        let converted = convert_image(gradient(32, 24), &settings, "a.png").unwrap();
+        assert_eq!(&converted.data[4..12], b"ftypavif");
+        assert_eq!(converted.dimensions, (32, 24));
+        assert_eq!(converted.quality, Some(80));
+        assert!(!converted.data.windows(4).any(|w| w == b"auxC"));
+    }
+
+    #[test]
+    fn avif_keeps_alpha_and_accepts_ten_bit() {
+        let rgba = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, Rgba([10, 20, 30, 40])));
+        let settings = ImageSettings {
+            compression: Some(10),
+            bit_depth: Some(10),
+            ..settings("avif")
+        };
+        let converted = convert_image(rgba, &settings, "a.png").unwrap();
+        // ravif stores alpha as a second, auxiliary image item
+        assert!(converted.data.windows(4).any(|w| w == b"auxC"));
+    }
+
+    #[test]
+    fn avif_rejects_unsupported_bit_depth() {
+        let settings = ImageSettings {
+            bit_depth: Some(12),
+            ..settings("avif")
+        };
+        let err = convert_image(gradient(8, 8), &settings, "a.png")
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "invalid_request");
+    }
+
+    #[test]
+    fn bit_depth_is_checked_before_any_file_is_decoded() {
+        let with_depth = |format: &str, depth: u8| ImageSettings {
+            bit_depth: Some(depth),
+            ..settings(format)
+        };
+        assert!(with_depth("avif", 8).validate().is_ok());
+        assert!(with_depth("avif", 10).validate().is_ok());
+        for settings in [
+            with_depth("avif", 12),
+            with_depth("avif", 0),
+            with_depth("png", 8),
+        ] {
+            assert!(
+                matches!(settings.validate(), Err(EngineError::InvalidRequest(_))),
+                "{} at {:?}",
+                settings.format,
+                settings.bit_depth
+            );
+        }
+
+        let auto = ImageSettings {
+            candidates: vec![candidate("webp"), candidate("avif")],
+            ..with_depth(AUTO_FORMAT, 10)
+        };
+        assert!(auto.validate().is_ok());
+    }
+
+    #[test]
+    fn convert_upload_applies_the_exif_orientation() {
+        let data = jpeg_with_orientation(32, 16, 6);
+        assert_eq!(read_orientation(&data), 6);
+
+        let upright = convert_upload(
+            "photo.jpg",
+            &data,
+            &settings("png"),
+            &upload_settings(),
+            || Ok(()),
+        )
+        .unwrap();
+        assert_eq!(upright.original_dimensions, (32, 16));
+        assert_eq!(upright.image.dimensions, (16, 32));
+        assert_eq!(
+            image::load_from_memory(&upright.image.data)
+                .unwrap()
+                .dimensions(),
+            (16, 32)
+        );
+
+        let stored = UploadSettings {
+            auto_orient: false,
+            ..upload_settings()
+        };
+        let kept =
+            convert_upload("photo.jpg", &data, &settings("png"), &stored, || Ok(())).unwrap();
+        assert_eq!(kept.image.dimensions, (32, 16));
+    }
+
+    #[test]
+    fn embed_mode_carries_the_upload_profile_into_the_output() {
+        let icc = include_bytes!("../testdata/display-p3.icc");
+        let upload = Metadata {
+            icc: Some(icc.to_vec()),
+            ..Metadata::default()
+        }
+        .embed("png", png_file(8, 8), (8, 8))
+        .unwrap();
+        let embed = UploadSettings {
+            color_profile: ColorProfileMode::Embed,
+            ..upload_settings()
+        };
+
+        for format in ["jpg", "png", "webp", "avif"] {
+            let converted =
+                convert_upload("p3.png", &upload, &settings(format), &embed, || Ok(())).unwrap();
+            assert_eq!(
+                converted.color_profile,
+                ProfileAction::Embedded,
+                "{}",
+                format
+            );
+            let data = &converted.image.data;
+            let carried = match format {
+                "avif" => data.windows(icc.len()).any(|window| window == icc),
+                _ => metadata::read_icc_profile(data).as_deref() == Some(&icc[..]),
+            };
+            assert!(carried, "{} output lost the profile", format);
+        }
+
+        let converted = convert_upload(
+            "p3.png",
+            &upload,
+            &settings("png"),
+            &upload_settings(),
+            || Ok(()),
+        )
+        .unwrap();
+        assert_eq!(converted.color_profile, ProfileAction::Converted);
+        assert_eq!(metadata::read_icc_profile(&converted.image.data), None);
+    }
+
+    fn candidate(format: &str) -> FormatCandidate {
+        FormatCandidate {
+            format: format.to_string(),
+            quality: None,
+            compression: None,
+        }
+    }
+
+    #[test]
+    fn max_bytes_needs_a_format_with_a_quality_setting() {
+        let with_max_bytes = |format: &str| ImageSettings {
+            max_bytes: Some(10_000),
+            ..settings(format)
+        };
+        for format in ["jpg", "webp", "avif"] {
+            assert!(with_max_bytes(format).validate().is_ok(), "{}", format);
+        }
+        for format in ["png", "bmp"] {
+            assert!(matches!(
+                with_max_bytes(format).validate(),
+                Err(EngineError::InvalidRequest(_))
+            ));
+        }
+        assert!(settings("png").validate().is_ok());
+
+        let lossless = ImageSettings {
+            webp: WebpOptions {
+                lossless: true,
+                ..WebpOptions::default()
+            },
+            ..with_max_bytes("webp")
+        };
+        match lossless.validate() {
+            Err(EngineError::InvalidRequest(message)) => {
+                assert!(message.contains("lossless webp"), "{}", message)
+            }
+            other => panic!("expected an invalid request, got {:?}", other),
+        }
+
+        // format=auto needs at least one candidate whose quality can be searched
+        let auto = |formats: &[&str]| ImageSettings {
+            candidates: formats.iter().map(|f| candidate(f)).collect(),
+            ..with_max_bytes(AUTO_FORMAT)
+        };
+        assert!(auto(&["png"]).validate().is_err());
+        assert!(auto(&["png", "jpg"]).validate().is_ok());
+    }
+
+    #[test]
+    fn size_search_keeps_the_highest_quality_that_fits() {
+        let img = gradient(96, 96);
+        let jpg = settings("jpg");
+        let max_bytes = encode(&img, &jpg, 50).unwrap().len() as u64;
+
+        let (_, data, quality) = encode_within(img.clone(), &jpg, 90, max_bytes, "a.jpg").unwrap();
+        assert!(data.len() as u64 <= max_bytes);
+        assert!((50..90).contains(&quality), "quality {}", quality);
+        assert!(encode(&img, &jpg, quality + 1).unwrap().len() as u64 > max_bytes);
+
+        // A budget the starting quality already meets is not searched
+        let (data, quality) = search_quality(&img, &jpg, 90, u64::MAX).unwrap().unwrap();
+        assert_eq!(quality, 90);
+        assert_eq!(data, encode(&img, &jpg, 90).unwrap());
+
+        // When nothing fits, the size at quality 1 is reported
+        let smallest = encode(&img, &jpg, 1).unwrap().len();
+        assert_eq!(search_quality(&img, &jpg, 90, 10).unwrap(), Err(smallest));
+    }
+
+    #[test]
+    fn downscale_to_fit_shrinks_until_the_output_fits() {
+        let img = gradient(512, 512);
+        let jpg = settings("jpg");
+        let max_bytes = encode(&img, &jpg, 1).unwrap().len() as u64 / 2;
+
+        assert!(matches!(
+            encode_within(img.clone(), &jpg, 80, max_bytes, "a.jpg"),
+            Err(EngineError::LimitExceeded(_))
+        ));
+
+        let downscale = ImageSettings {
+            downscale_to_fit: true,
+            ..jpg
+        };
+        let (scaled, data, _) = encode_within(img, &downscale, 80, max_bytes, "a.jpg").unwrap();
+        assert!(data.len() as u64 <= max_bytes);
+        let (width, height) = scaled.dimensions();
+        assert!(width < 512 && width == height, "{}x{}", width, height);
+    }
+
+    #[test]
+    fn target_ssim_needs_a_format_it_can_be_measured_on() {
+        let with_target = |format: &str| ImageSettings {
+            target_ssim: Some(0.95),
+            ..settings(format)
+        };
+        for format in ["jpg", "webp", "png", "bmp"] {
+            assert!(with_target(format).validate().is_ok(), "{}", format);
+        }
+        match with_target("avif").validate() {
+            Err(EngineError::InvalidRequest(message)) => {
+                assert!(message.contains("avif"), "{}", message)
+            }
+            other => panic!("expected an invalid request, got {:?}", other),
+        }
+
+        let auto = |formats: &[&str]| ImageSettings {
+            candidates: formats.iter().map(|f| candidate(f)).collect(),
+            ..with_target(AUTO_FORMAT)
+        };
+        assert!(auto(&["avif"]).validate().is_err());
+        assert!(auto(&["avif", "webp"]).validate().is_ok());
+    }
+
+    #[test]
+    fn ssim_search_picks_the_lowest_quality_reaching_the_target() {
+        let img = gradient(64, 64);
+        let jpg = settings("jpg");
+        let target = 0.95;
+
+        let (quality, data, score) = search_ssim(&img, &jpg, target, "a.jpg").unwrap();
+        assert!(score >= target, "SSIM {} at quality {}", score, quality);
+        assert_eq!(measure_ssim(&img, &jpg, &data).unwrap(), score);
+        assert!(quality > 1);
+        let lower = encode(&img, &jpg, quality - 1).unwrap();
+        assert!(measure_ssim(&img, &jpg, &lower).unwrap() < target);
+
+        assert!(matches!(
+            search_ssim(&img, &settings("avif"), target, "a.avif"),
+            Err(EngineError::InvalidRequest(_))
+        ));
+    }
+
+    fn auto(formats: &[&str]) -> ImageSettings {
+        ImageSettings {
+            candidates: formats.iter().map(|f| candidate(f)).collect(),
+            ..settings(AUTO_FORMAT)
+        }
+    }
+
+    // The gradient with its left half fully transparent
+    fn half_transparent(width: u32, height: u32) -> DynamicImage {
+        let mut rgba = gradient(width, height).to_rgba8();
+        for (x, _, pixel) in rgba.enumerate_pixels_mut() {
+            if x < width / 2 {
+                pixel[3] = 0;
+            }
+        }
+        DynamicImage::ImageRgba8(rgba)
+    }
+
+    #[test]
+    fn transparency_needs_a_pixel_below_full_alpha() {
+        assert!(!has_transparency(&gradient(4, 4)));
+        let opaque = DynamicImage::ImageRgba8(gradient(4, 4).to_rgba8());
+        assert!(!has_transparency(&opaque));
+        assert!(has_transparency(&half_transparent(4, 4)));
+
+        let mut luma = image::GrayAlphaImage::from_pixel(4, 4, image::LumaA([9, 255]));
+        assert!(!has_transparency(&DynamicImage::ImageLumaA8(luma.clone())));
+        luma.put_pixel(3, 3, image::LumaA([9, 254]));
+        assert!(has_transparency(&DynamicImage::ImageLumaA8(luma)));
+        let wide = DynamicImage::ImageRgba16(half_transparent(4, 4).to_rgba16());
+        assert!(has_transparency(&wide));
+    }
+
+    #[test]
+    fn auto_never_picks_jpg_for_transparent_images() {
+        let img = half_transparent(32, 32);
+        let converted = encode_smallest(img.clone(), &auto(&["jpg", "png"]), "a.png").unwrap();
+        assert_eq!(converted.format, "png");
+
+        match encode_smallest(img, &auto(&["jpg"]), "a.png") {
+            Err(EngineError::InvalidRequest(message)) => {
+                assert!(message.contains("a.png"), "{}", message)
+            }
+            other => panic!(
+                "expected an invalid request, got {:?}",
+                other.map(|c| c.format)
+            ),
+        }
+    }
+
+    #[test]
+    fn auto_keeps_the_smallest_candidate() {
+        let img = gradient(48, 48);
+        let formats = ["png", "jpg", "webp", "avif"];
+        let sizes: Vec<(usize, &str)> = formats
+            .iter()
+            .map(|&format| {
+                let converted = encode_image(img.clone(), &settings(format), "a.png").unwrap();
+                (converted.data.len(), format)
+            })
+            .collect();
+        let (size, format) = *sizes.iter().min().unwrap();
+
+        let converted = encode_smallest(img.clone(), &auto(&formats), "a.png").unwrap();
+        assert_eq!(converted.format, format, "sizes {:?}", sizes);
+        assert_eq!(converted.data.len(), size);
+
+        // With a similarity target, AVIF cannot be measured and drops out
+        let target = ImageSettings {
+            target_ssim: Some(0.9),
+            ..auto(&["avif", "jpg"])
+        };
+        let converted = encode_smallest(img, &target, "a.png").unwrap();
+        assert_eq!(converted.format, "jpg");
+        assert!(converted.ssim.unwrap() >= 0.9);
+    }
+
+    fn webp(options: WebpOptions) -> ImageSettings {
+        ImageSettings {
+            webp: options,
+            ..settings("webp")
+        }
+    }
+
+    #[test]
+    fn webp_levels_must_be_in_range() {
+        let valid = [
+            WebpOptions::default(),
+            WebpOptions {
+                near_lossless: Some(0),
+                alpha_quality: Some(0),
+                method: Some(0),
+                ..WebpOptions::default()
+            },
+            WebpOptions {
+                near_lossless: Some(100),
+                alpha_quality: Some(100),
+                method: Some(6),
+                ..WebpOptions::default()
+            },
+        ];
+        for options in valid {
+            assert!(options.validate().is_ok(), "{:?}", options);
+            assert!(webp(options).validate().is_ok(), "{:?}", options);
+        }
+
+        let invalid = [
+            WebpOptions {
+                near_lossless: Some(101),
+                ..WebpOptions::default()
+            },
+            WebpOptions {
+                alpha_quality: Some(101),
+                ..WebpOptions::default()
+            },
+            WebpOptions {
+                method: Some(7),
+                ..WebpOptions::default()
+            },
+        ];
+        for options in invalid {
+            assert!(
+                matches!(options.validate(), Err(EngineError::InvalidRequest(_))),
+                "{:?}",
+                options
+            );
+            assert!(webp(options).validate().is_err(), "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn lossless_webp_has_no_quality_to_search() {
+        let lossless = WebpOptions {
+            lossless: true,
+            ..WebpOptions::default()
+        };
+        let near_lossless = WebpOptions {
+            near_lossless: Some(60),
+            ..WebpOptions::default()
+        };
+        for options in [lossless, near_lossless] {
+            assert!(options.is_lossless());
+            let with_max_bytes = ImageSettings {
+                max_bytes: Some(10_000),
+                ..webp(options)
+            };
+            assert!(with_max_bytes.validate().is_err(), "{:?}", options);
+
+            // The quality only sets the effort, so the manifest does not report it
+            let settings = ImageSettings {
+                quality: Some(10),
+                ..webp(options)
+            };
+            let converted = encode_image(gradient(16, 16), &settings, "a.png").unwrap();
+            assert_eq!(converted.quality, None);
+        }
+        assert!(!WebpOptions::default().is_lossless());
+    }
+
+    #[test]
+    fn webp_options_reach_the_encoder_config() {
+        let config = WebpOptions::default().config(75).unwrap();
+        assert_eq!((config.lossless, config.alpha_compression), (0, 1));
+        assert_eq!(config.quality, 75.0);
+
+        let config = WebpOptions {
+            near_lossless: Some(40),
+            method: Some(6),
+            exact: true,
+            ..WebpOptions::default()
+        }
+        .config(20)
+        .unwrap();
+        assert_eq!((config.lossless, config.alpha_compression), (1, 0));
+        assert_eq!(
+            (config.near_lossless, config.method, config.exact),
+            (40, 6, 1)
+        );
+
+        let config = WebpOptions {
+            alpha_quality: Some(30),
+            ..WebpOptions::default()
+        }
+        .config(80)
+        .unwrap();
+        assert_eq!(config.alpha_quality, 30);
+    }
+
+    #[test]
+    fn lossless_webp_decodes_to_the_same_pixels() {
+        let decode = |data: &[u8]| {
+            webp::Decoder::new(data)
+                .decode()
+                .unwrap()
+                .to_image()
+                .to_rgba8()
+        };
+        let exact = WebpOptions {
+            lossless: true,
+            exact: true,
+            ..WebpOptions::default()
+        };
+        for img in [gradient(24, 16), half_transparent(24, 16)] {
+            let converted = encode_image(img.clone(), &webp(exact), "a.png").unwrap();
+            assert!(decode(&converted.data) == img.to_rgba8());
+        }
+
+        let img = gradient(24, 16);
+        let lossy = encode_image(img.clone(), &settings("webp"), "a.png").unwrap();
+        assert!(decode(&lossy.data) != img.to_rgba8());
+    }
+
+    #[test]
+    fn encode_time_is_labeled_with_the_chosen_format() {
+        let encoded = |format: &str| {
+            METRICS
+                .encode_seconds
+                .with_label_values(&[format])
+                .get_sample_count()
+        };
+        let before = encoded("png");
+        let converted = convert_image(gradient(8, 8), &auto(&["png"]), "a.png").unwrap();
+        assert_eq!(converted.format, "png");
+        assert!(encoded("png") > before);
+        assert!(!METRICS
+            .render()
+            .lines()
+            .any(|line| line.starts_with("convertxion_encode_seconds")
+                && line.contains("format=\"auto\"")));
+    }
 }
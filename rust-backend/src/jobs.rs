@@ -0,0 +1,251 @@
+use crate::error::EngineError;
+use bytes::Bytes;
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+struct Job {
+    session_id: String,
+    status: JobStatus,
+    created_at: u64,
+    finished_at: Option<Instant>,
+    result: Option<Bytes>,
+    error: Option<EngineError>,
+}
+
+/// Public view of a job, returned by `GET /api/jobs/{id}`.
+#[derive(Debug, Serialize)]
+pub struct JobInfo {
+    pub job_id: String,
+    pub session_id: String,
+    pub status: JobStatus,
+    /// Unix timestamp (seconds) when the job was accepted.
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Seconds left before a finished job and its result are removed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+}
+
+/// In-memory registry of asynchronous conversion jobs.
+/// Finished jobs are kept for `ttl` and then dropped by [`JobStore::purge_expired`].
+#[derive(Clone)]
+pub struct JobStore {
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    ttl: Duration,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration) -> Self {
+        JobStore {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// Registers a new job as running.
+    pub fn create(&self, job_id: String, session_id: String) {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.jobs.lock().unwrap().insert(
+            job_id,
+            Job {
+                session_id,
+                status: JobStatus::Running,
+                created_at,
+                finished_at: None,
+                result: None,
+                error: None,
+            },
+        );
+    }
+
+    /// Stores the outcome of a job and starts its TTL.
+    pub fn finish(&self, job_id: &str, outcome: Result<Vec<u8>, EngineError>) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(job_id) {
+            job.finished_at = Some(Instant::now());
+            match outcome {
+                Ok(data) => {
+                    job.status = JobStatus::Done;
+                    job.result = Some(Bytes::from(data));
+                }
+                Err(e) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(e);
+                }
+            }
+        }
+    }
+
+    pub fn info(&self, job_id: &str) -> Option<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(job_id)?;
+        Some(JobInfo {
+            job_id: job_id.to_string(),
+            session_id: job.session_id.clone(),
+            status: job.status,
+            created_at: job.created_at,
+            result_size: job.result.as_ref().map(|data| data.len()),
+            error_code: job.error.as_ref().map(|e| e.code()),
+            error: job.error.as_ref().map(|e| e.to_string()),
+            expires_in_secs: job
+                .finished_at
+                .map(|at| self.ttl.saturating_sub(at.elapsed()).as_secs()),
+        })
+    }
+
+    /// Runs `f` with the finished ZIP (a cheap, shared handle), or with the error that
+    /// ended the job.
+    pub fn with_result<T>(
+        &self,
+        job_id: &str,
+        f: impl FnOnce(Result<Bytes, &EngineError>) -> T,
+    ) -> Result<T, EngineError> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get(job_id)
+            .ok_or_else(|| EngineError::JobNotFound(job_id.to_string()))?;
+        match (&job.result, &job.error) {
+            (Some(data), _) => Ok(f(Ok(data.clone()))),
+            (None, Some(e)) => Ok(f(Err(e))),
+            (None, None) => Err(EngineError::JobNotReady(job_id.to_string())),
+        }
+    }
+
+    /// Number of jobs that have not finished yet.
+    pub fn running(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.status == JobStatus::Running)
+            .count()
+    }
+
+    /// Waits until every running job has finished or `timeout` has passed.
+    /// Returns how many jobs were still running when it gave up.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let running = self.running();
+            if running == 0 || Instant::now() >= deadline {
+                return running;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    /// Drops finished jobs whose TTL has run out.
+    pub fn purge_expired(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|_, job| job.finished_at.is_none_or(|at| at.elapsed() < self.ttl));
+        let removed = before - jobs.len();
+        if removed > 0 {
+            info!("🧹 Removed {} expired job(s)", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_goes_from_running_to_a_downloadable_result() {
+        let jobs = JobStore::new(Duration::from_secs(60));
+        jobs.create("job".into(), "session".into());
+
+        let info = jobs.info("job").unwrap();
+        assert_eq!(info.status, JobStatus::Running);
+        assert_eq!(info.result_size, None);
+        assert_eq!(jobs.running(), 1);
+        let not_ready = jobs.with_result("job", |_| ()).unwrap_err();
+        assert_eq!(not_ready.code(), "job_not_ready");
+
+        jobs.finish("job", Ok(vec![1, 2, 3]));
+        let info = jobs.info("job").unwrap();
+        assert_eq!(info.status, JobStatus::Done);
+        assert_eq!(info.result_size, Some(3));
+        assert!(info.expires_in_secs.is_some());
+        assert_eq!(jobs.running(), 0);
+
+        let first = jobs.with_result("job", |r| r.unwrap()).unwrap();
+        let second = jobs.with_result("job", |r| r.unwrap()).unwrap();
+        assert_eq!(&first[..], [1, 2, 3]);
+        // Downloads share the stored archive instead of copying it
+        assert_eq!(first.as_ptr(), second.as_ptr());
+    }
+
+    #[test]
+    fn failed_job_reports_its_error() {
+        let jobs = JobStore::new(Duration::from_secs(60));
+        jobs.create("job".into(), "session".into());
+        jobs.finish("job", Err(EngineError::Canceled));
+
+        let info = jobs.info("job").unwrap();
+        assert_eq!(info.status, JobStatus::Failed);
+        assert_eq!(info.error_code, Some("canceled"));
+        let code = jobs.with_result("job", |r| r.unwrap_err().code()).unwrap();
+        assert_eq!(code, "canceled");
+    }
+
+    #[test]
+    fn unknown_and_expired_jobs_are_not_found() {
+        let jobs = JobStore::new(Duration::ZERO);
+        assert!(jobs.info("missing").is_none());
+        let err = jobs.with_result("missing", |_| ()).unwrap_err();
+        assert_eq!(err.code(), "job_not_found");
+
+        jobs.create("running".into(), "session".into());
+        jobs.create("done".into(), "session".into());
+        jobs.finish("done", Ok(Vec::new()));
+        jobs.purge_expired();
+        // Running jobs are kept however old they are
+        assert!(jobs.info("running").is_some());
+        assert!(jobs.info("done").is_none());
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_running_jobs_to_finish() {
+        let jobs = JobStore::new(Duration::from_secs(60));
+        jobs.create("job".into(), "session".into());
+        let finisher = jobs.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            finisher.finish("job", Ok(Vec::new()));
+        });
+
+        assert_eq!(jobs.drain(Duration::from_secs(5)).await, 0);
+        assert_eq!(jobs.info("job").unwrap().status, JobStatus::Done);
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_at_the_deadline() {
+        let jobs = JobStore::new(Duration::from_secs(60));
+        jobs.create("stuck".into(), "session".into());
+        jobs.create("done".into(), "session".into());
+        jobs.finish("done", Ok(Vec::new()));
+
+        let started = Instant::now();
+        assert_eq!(jobs.drain(Duration::from_millis(300)).await, 1);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
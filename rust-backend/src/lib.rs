@@ -0,0 +1,49 @@
+//! Image conversion engine behind ConvertXion.
+//!
+//! The core converts single images or whole batches and reports progress through
+//! [`ProgressSink`]:
+//!
+//! - `image_utils`: decoding, encoding and the batch pipeline
+//! - `resize`, `transform`, `color`, `metadata`: pixel and metadata steps of a conversion
+//! - `ssim`: quality measurement for `target_ssim`
+//! - `scheduler`, `progress`, `metrics`: memory budget, progress events and Prometheus metrics
+//! - `app_config`, `error`: configuration and the error codes clients see
+//!
+//! The default `server` feature adds the actix HTTP API and WebSocket progress that
+//! `src/main.rs` serves; the default `cli` feature builds the `convertxion` tool.
+
+pub mod app_config;
+pub mod color;
+pub mod error;
+pub mod image_utils;
+pub mod metadata;
+pub mod metrics;
+pub mod progress;
+pub mod resize;
+pub mod scheduler;
+pub mod ssim;
+pub mod transform;
+
+#[cfg(feature = "server")]
+pub mod handlers;
+#[cfg(feature = "server")]
+pub mod jobs;
+#[cfg(feature = "server")]
+pub mod routes;
+#[cfg(feature = "server")]
+pub mod sessions;
+#[cfg(feature = "server")]
+pub mod ws_handler;
+
+pub use app_config::AppConfig;
+pub use color::{ColorProfileMode, ProfileAction};
+pub use error::EngineError;
+pub use image_utils::{
+    convert_image, decode_image, process_images, BatchOptions, CancelToken, ConvertedImage,
+    DecodeLimits, FileResult, FileStatus, ImageSettings, UploadSettings,
+};
+pub use metadata::{Metadata, MetadataPolicy};
+pub use progress::{ProgressEvent, ProgressReporter, ProgressSink};
+pub use resize::{Gravity, ResizeMode};
+pub use scheduler::MemoryScheduler;
+pub use transform::Transform;
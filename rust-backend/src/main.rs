@@ -3,18 +3,15 @@ use actix_web::{http, web, App, HttpServer};
 use crossterm::event::{self, Event, KeyCode};
 use dotenv::dotenv;
 use env_logger::Env;
-use log::info;
+use log::{info, warn};
 use std::collections::HashMap;
-use std::env;
+use std::io::IsTerminal;
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
-use tokio::sync::Notify;
-use ws_handler::ProgressChannels;
+use std::time::Duration;
+use tokio::sync::oneshot;
 
-mod handlers;
-mod image_utils;
-mod routes;
-mod ws_handler;
+use convertxion_engine::ws_handler::ProgressChannels;
+use convertxion_engine::{error, handlers, jobs, routes, sessions, AppConfig, MemoryScheduler};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -22,78 +19,156 @@ async fn main() -> std::io::Result<()> {
 
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
+    // Config file plus env overrides, read once for the whole process
+    let config = AppConfig::load().map_err(|e| {
+        log::error!("Invalid configuration: {}", e);
+        std::io::Error::other(e)
+    })?;
+    config.log_summary();
+
     let progress_channels: ProgressChannels = Arc::new(Mutex::new(HashMap::new()));
 
-    // Get server IP and port from .env
-    let ip = env::var("SERVER_IP").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string());
-    let server_address = format!("{}:{}", ip, port);
+    let server_address = format!("{}:{}", config.server.ip, config.server.port);
 
-    // Global state for cancellation
-    let app_state = handlers::AppState {
-        cancel_flag: Arc::new(Mutex::new(false)),
-        cancel_notify: Arc::new(Notify::new()),
-    };
+    // Per-session cancellation state, plus one memory budget for every conversion
+    let app_state = handlers::AppState::new(MemoryScheduler::new(config.memory_budget()));
 
-    // Graceful shutdown logic
-    let (shutdown_sender, mut shutdown_receiver) = mpsc::channel::<String>(1);
-    shutdown_sender
-        .send("Shutting down...".to_string())
-        .await
-        .unwrap();
+    // Finished async jobs are kept for jobs.result_ttl_secs, then cleaned up
+    let job_store = jobs::JobStore::new(Duration::from_secs(config.jobs.result_ttl_secs));
 
-    if let Some(message) = shutdown_receiver.recv().await {
-        info!("{}", message);
-    }
+    // Issued session IDs expire after sessions.ttl_secs without use
+    let session_store = sessions::SessionStore::new(
+        Duration::from_secs(config.sessions.ttl_secs),
+        config.sessions.max_sessions,
+    );
+
+    let cleanup_jobs = job_store.clone();
+    let cleanup_sessions = session_store.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            cleanup_jobs.purge_expired();
+            cleanup_sessions.purge_expired();
+        }
+    });
 
-    let allowed_origin =
-        env::var("ALLOWED_ORIGIN").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    // In-flight requests and async jobs get server.shutdown_timeout_secs to finish on shutdown
+    let shutdown_timeout = config.server.shutdown_timeout_secs;
+    let allowed_origin = config.server.allowed_origin.clone();
 
     // Start the server
-    let server = HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin(&allowed_origin)
-            .allowed_methods(vec!["GET", "POST", "OPTIONS"])
-            .allowed_headers(vec![
-                http::header::AUTHORIZATION,
-                http::header::ACCEPT,
-                http::header::CONTENT_TYPE,
-            ])
-            .supports_credentials()
-            .max_age(1200);
-
-        App::new()
-            .app_data(web::Data::new(app_state.clone())) // Add global state
-            .app_data(web::Data::new(progress_channels.clone()))
-            .wrap(cors)
-            .configure(routes::config)
-    })
-    .bind(&server_address)?
-    .run();
+    let drain_jobs = job_store.clone();
+    let server =
+        HttpServer::new(move || {
+            let cors = Cors::default()
+                .allowed_origin(&allowed_origin)
+                .allowed_methods(vec!["GET", "POST", "OPTIONS"])
+                .allowed_headers(vec![
+                    http::header::AUTHORIZATION,
+                    http::header::ACCEPT,
+                    http::header::CONTENT_TYPE,
+                ])
+                .supports_credentials()
+                .max_age(1200);
+
+            App::new()
+                .app_data(web::Data::new(app_state.clone())) // Add global state
+                .app_data(web::Data::new(progress_channels.clone()))
+                .app_data(web::Data::new(job_store.clone()))
+                .app_data(web::Data::new(session_store.clone()))
+                .app_data(web::Data::new(config.clone()))
+                // Malformed query strings get the same JSON error body as every other failure
+                .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                    error::EngineError::InvalidRequest(err.to_string()).into()
+                }))
+                .wrap(cors)
+                .configure(routes::config)
+        })
+        .bind(&server_address)?
+        // Signals are handled below, together with the async jobs
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout)
+        .run();
 
     info!("Starting server at http://{}", server_address);
-    info!("Press 'q' to shut down the server.");
 
     // Graceful shutdown listener
     let server_handle = server.handle();
-    tokio::spawn(async move {
-        loop {
-            if event::poll(std::time::Duration::from_millis(100)).unwrap() {
-                if let Event::Key(key_event) = event::read().unwrap() {
-                    if key_event.code == KeyCode::Char('q') {
-                        info!("Received 'q' key. Shutting down...");
-                        break;
-                    }
-                    if key_event.code == KeyCode::Char('a') {
-                        info!("Received 'a' key. Shutting down...");
-                        break;
+    let shutdown = tokio::spawn(async move {
+        shutdown_signal().await;
+        info!(
+            "Shutting down, waiting up to {}s for running conversions...",
+            shutdown_timeout
+        );
+        let (_, abandoned) = tokio::join!(
+            server_handle.stop(true),
+            drain_jobs.drain(Duration::from_secs(shutdown_timeout))
+        );
+        if abandoned > 0 {
+            warn!("Abandoning {} job(s) still running", abandoned);
+        }
+    });
+
+    // Wait for the server to finish, then for the jobs it left behind
+    server.await?;
+    let _ = shutdown.await;
+    info!("Server stopped");
+    Ok(())
+}
+
+// Resolves on SIGTERM, SIGINT, or 'q' / 'a' typed on an interactive terminal
+async fn shutdown_signal() {
+    let keys = key_listener();
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+        Ok(key) = keys => info!("Received '{}' key", key),
+    }
+}
+
+// Watches the keyboard on its own thread. Without a TTY (Docker, Railway) the sender is
+// dropped right away, which disables the key branch in `shutdown_signal`.
+fn key_listener() -> oneshot::Receiver<char> {
+    let (tx, rx) = oneshot::channel();
+    if !std::io::stdin().is_terminal() {
+        info!("No terminal attached; stop the server with SIGTERM or SIGINT.");
+        return rx;
+    }
+
+    info!("Press 'q' to shut down the server.");
+    std::thread::spawn(move || loop {
+        match event::poll(Duration::from_millis(100)) {
+            Ok(true) => {
+                if let Ok(Event::Key(key_event)) = event::read() {
+                    if let KeyCode::Char(key @ ('q' | 'a')) = key_event.code {
+                        let _ = tx.send(key);
+                        return;
                     }
                 }
             }
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Keyboard listener stopped: {}", e);
+                return;
+            }
         }
-        server_handle.stop(true).await; // Graceful shutdown
     });
-
-    // Wait for the server to finish
-    server.await
+    rx
 }
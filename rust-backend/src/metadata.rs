@@ -0,0 +1,1017 @@
+use crate::error::EngineError;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Read, Write};
+use std::str::FromStr;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+// Largest payload of one JPEG segment (the length field counts itself)
+const JPEG_SEGMENT_MAX: usize = 65533;
+const TIFF_ORIENTATION: u16 = 0x0112;
+// Largest ICC profile or XMP packet inflated from a PNG; a few KB of zlib can otherwise
+// expand to gigabytes
+const INFLATE_LIMIT: usize = 4 * 1024 * 1024;
+
+/// What happens to the upload's EXIF and XMP in the converted file.
+/// The ICC profile is handled by [`ColorProfileMode`](crate::color::ColorProfileMode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataPolicy {
+    /// Write no metadata at all.
+    #[default]
+    Strip,
+    /// Copy EXIF and XMP unchanged.
+    Keep,
+    /// Keep only the copyright notice and the orientation; GPS position, camera serial
+    /// numbers, XMP and everything else is dropped.
+    Safe,
+}
+
+impl FromStr for MetadataPolicy {
+    type Err = EngineError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "strip" => Ok(MetadataPolicy::Strip),
+            "keep" => Ok(MetadataPolicy::Keep),
+            "safe" => Ok(MetadataPolicy::Safe),
+            other => Err(EngineError::InvalidRequest(format!(
+                "Unknown metadata policy '{}' (expected strip, keep or safe)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Metadata taken from an upload, to be written into the converted file.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// EXIF as a TIFF structure, without the `Exif\0\0` prefix.
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads the EXIF and XMP of a JPEG, PNG or WebP upload that `policy` allows to keep.
+    /// The ICC profile is left out; it follows the color profile mode instead (see
+    /// [`read_icc_profile`]). Anything unreadable is treated as missing.
+    pub fn read(data: &[u8], policy: MetadataPolicy) -> Self {
+        let metadata = match policy {
+            MetadataPolicy::Strip => return Metadata::default(),
+            _ => read_container(data, false),
+        };
+
+        match policy {
+            MetadataPolicy::Safe => Metadata {
+                exif: metadata.exif.and_then(safe_exif),
+                xmp: None,
+                icc: None,
+            },
+            _ => Metadata {
+                icc: None,
+                ..metadata
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.icc.is_none()
+    }
+
+    /// Sets the EXIF orientation to 1, once the pixels themselves have been turned upright.
+    pub fn mark_upright(&mut self) {
+        if let Some(exif) = &mut self.exif {
+            reset_orientation(exif);
+        }
+    }
+
+    /// Upper bound on the bytes [`Self::embed`] adds to a `format` file, so a size budget
+    /// can leave room for it before encoding.
+    pub fn embedded_size(&self, format: &str) -> usize {
+        // Generous per-block framing: segment and chunk headers, CRCs, zlib and box overhead
+        let framed = |block: &Option<Vec<u8>>| block.as_ref().map_or(0, |b| b.len() + 64);
+        match format {
+            "jpg" | "png" | "webp" => {
+                // JPEG splits profiles over several APP2 segments
+                let icc_segments = self.icc.as_ref().map_or(0, |icc| icc.len() / 65_000);
+                framed(&self.exif) + framed(&self.xmp) + framed(&self.icc) + icc_segments * 64
+            }
+            "avif" => framed(&self.icc),
+            _ => 0,
+        }
+    }
+
+    /// Writes the metadata into an encoded `format` file of `dimensions`.
+    /// Formats that cannot carry metadata are returned unchanged.
+    pub fn embed(
+        &self,
+        format: &str,
+        encoded: Vec<u8>,
+        dimensions: (u32, u32),
+    ) -> Result<Vec<u8>, EngineError> {
+        if self.is_empty() {
+            return Ok(encoded);
+        }
+        match format {
+            "jpg" => self.embed_jpeg(encoded),
+            "png" => self.embed_png(encoded),
+            "webp" => self.embed_webp(encoded, dimensions),
+            "avif" => {
+                if self.exif.is_some() || self.xmp.is_some() {
+                    debug!("avif output only carries the ICC profile, dropping EXIF and XMP");
+                }
+                match &self.icc {
+                    Some(icc) => embed_avif_icc(&encoded, icc)
+                        .ok_or_else(|| EngineError::encode("avif", "cannot embed ICC profile")),
+                    None => Ok(encoded),
+                }
+            }
+            _ => {
+                debug!("{} output cannot carry metadata, dropping it", format);
+                Ok(encoded)
+            }
+        }
+    }
+
+    // APP1 Exif, APP1 XMP and APP2 ICC segments, right after SOI and the JFIF header
+    fn embed_jpeg(&self, encoded: Vec<u8>) -> Result<Vec<u8>, EngineError> {
+        if !encoded.starts_with(&[0xFF, 0xD8]) {
+            return Err(EngineError::encode("jpg", "unexpected encoder output"));
+        }
+        let mut segments = Vec::new();
+        let mut push_segment = |marker: u8, header: &[u8], payload: &[u8]| {
+            segments.extend_from_slice(&[0xFF, marker]);
+            segments.extend_from_slice(&((header.len() + payload.len() + 2) as u16).to_be_bytes());
+            segments.extend_from_slice(header);
+            segments.extend_from_slice(payload);
+        };
+
+        if let Some(exif) = &self.exif {
+            if EXIF_HEADER.len() + exif.len() <= JPEG_SEGMENT_MAX {
+                push_segment(0xE1, EXIF_HEADER, exif);
+            } else {
+                warn!("EXIF block too large for a JPEG segment, dropping it");
+            }
+        }
+        if let Some(xmp) = &self.xmp {
+            if XMP_HEADER.len() + xmp.len() <= JPEG_SEGMENT_MAX {
+                push_segment(0xE1, XMP_HEADER, xmp);
+            } else {
+                warn!("XMP packet too large for a JPEG segment, dropping it");
+            }
+        }
+        if let Some(icc) = &self.icc {
+            // Profiles are split over numbered APP2 segments
+            let chunks: Vec<&[u8]> = icc
+                .chunks(JPEG_SEGMENT_MAX - ICC_HEADER.len() - 2)
+                .collect();
+            if chunks.len() <= u8::MAX as usize {
+                for (index, chunk) in chunks.iter().enumerate() {
+                    let mut header = ICC_HEADER.to_vec();
+                    header.extend_from_slice(&[index as u8 + 1, chunks.len() as u8]);
+                    push_segment(0xE2, &header, chunk);
+                }
+            } else {
+                warn!("ICC profile too large for JPEG, dropping it");
+            }
+        }
+
+        let mut position = 2;
+        if encoded.get(2..4) == Some(&[0xFF, 0xE0]) {
+            position += 2 + be_u16(&encoded, 4).unwrap_or(0) as usize;
+        }
+        let position = position.min(encoded.len());
+
+        let mut output = Vec::with_capacity(encoded.len() + segments.len());
+        output.extend_from_slice(&encoded[..position]);
+        output.extend_from_slice(&segments);
+        output.extend_from_slice(&encoded[position..]);
+        Ok(output)
+    }
+
+    // iCCP, eXIf and iTXt chunks, right after IHDR
+    fn embed_png(&self, encoded: Vec<u8>) -> Result<Vec<u8>, EngineError> {
+        let mut chunks = Vec::new();
+        if let Some(icc) = &self.icc {
+            let mut data = b"ICC Profile\0\0".to_vec();
+            let mut encoder = ZlibEncoder::new(&mut data, Compression::default());
+            encoder
+                .write_all(icc)
+                .and_then(|_| encoder.finish().map(|_| ()))
+                .map_err(|e| EngineError::encode("png", e))?;
+            push_png_chunk(&mut chunks, b"iCCP", &data);
+        }
+        if let Some(exif) = &self.exif {
+            push_png_chunk(&mut chunks, b"eXIf", exif);
+        }
+        if let Some(xmp) = &self.xmp {
+            // Uncompressed, with empty language and translated keyword
+            let mut data = PNG_XMP_KEYWORD.to_vec();
+            data.extend_from_slice(&[0, 0, 0, 0, 0]);
+            data.extend_from_slice(xmp);
+            push_png_chunk(&mut chunks, b"iTXt", &data);
+        }
+
+        // Signature plus the IHDR chunk (length, type, 13 bytes of data, CRC)
+        let position = PNG_SIGNATURE.len() + 25;
+        if encoded.len() < position || encoded.get(12..16) != Some(b"IHDR") {
+            return Err(EngineError::encode("png", "unexpected encoder output"));
+        }
+        let mut output = Vec::with_capacity(encoded.len() + chunks.len());
+        output.extend_from_slice(&encoded[..position]);
+        output.extend_from_slice(&chunks);
+        output.extend_from_slice(&encoded[position..]);
+        Ok(output)
+    }
+
+    // Rebuilds the RIFF container in the extended (VP8X) layout with ICCP, EXIF and XMP chunks
+    fn embed_webp(
+        &self,
+        encoded: Vec<u8>,
+        (width, height): (u32, u32),
+    ) -> Result<Vec<u8>, EngineError> {
+        let chunks = webp_chunks(&encoded)
+            .ok_or_else(|| EngineError::encode("webp", "unexpected encoder output"))?;
+
+        let mut flags = 0u8;
+        let mut image_chunks = Vec::new();
+        for (fourcc, data) in chunks {
+            match fourcc {
+                b"VP8X" => flags = data.first().copied().unwrap_or(0),
+                b"ICCP" | b"EXIF" | b"XMP " => {}
+                _ => {
+                    // A lossless bitstream records whether it uses alpha in its header
+                    if fourcc == b"VP8L" && data.len() >= 5 {
+                        let header = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+                        if header >> 28 & 1 == 1 {
+                            flags |= 0x10;
+                        }
+                    }
+                    if fourcc == b"ALPH" {
+                        flags |= 0x10;
+                    }
+                    image_chunks.push((fourcc, data));
+                }
+            }
+        }
+        flags &= !(0x20 | 0x08 | 0x04);
+        if self.icc.is_some() {
+            flags |= 0x20;
+        }
+        if self.exif.is_some() {
+            flags |= 0x08;
+        }
+        if self.xmp.is_some() {
+            flags |= 0x04;
+        }
+
+        let mut vp8x = vec![flags, 0, 0, 0];
+        vp8x.extend_from_slice(&(width.max(1) - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height.max(1) - 1).to_le_bytes()[..3]);
+
+        let mut body = b"WEBP".to_vec();
+        push_webp_chunk(&mut body, b"VP8X", &vp8x);
+        if let Some(icc) = &self.icc {
+            push_webp_chunk(&mut body, b"ICCP", icc);
+        }
+        for (fourcc, data) in image_chunks {
+            push_webp_chunk(&mut body, fourcc, data);
+        }
+        if let Some(exif) = &self.exif {
+            push_webp_chunk(&mut body, b"EXIF", exif);
+        }
+        if let Some(xmp) = &self.xmp {
+            push_webp_chunk(&mut body, b"XMP ", xmp);
+        }
+
+        let mut output = b"RIFF".to_vec();
+        output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        output.extend_from_slice(&body);
+        Ok(output)
+    }
+}
+
+/// The ICC profile embedded in a JPEG, PNG or WebP upload. Other metadata is not parsed,
+/// so this is cheap enough to run on every upload.
+pub fn read_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    read_container(data, true).icc
+}
+
+// With `icc_only`, PNG text chunks are skipped instead of inflated
+fn read_container(data: &[u8], icc_only: bool) -> Metadata {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        read_jpeg(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        read_png(data, icc_only)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        read_webp(data)
+    } else {
+        Metadata::default()
+    }
+}
+
+fn read_jpeg(data: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    let mut icc_chunks = Vec::new();
+    let mut position = 2;
+
+    while position + 4 <= data.len() && data[position] == 0xFF {
+        let marker = data[position + 1];
+        // Fill bytes and standalone markers have no length
+        if marker == 0xFF || marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            position += if marker == 0xFF { 1 } else { 2 };
+            continue;
+        }
+        // Metadata only lives in the header, before the scan data
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let Some(length) = be_u16(data, position + 2).map(usize::from) else {
+            break;
+        };
+        let Some(segment) = data.get(position + 4..position + 2 + length) else {
+            break;
+        };
+        match marker {
+            0xE1 if segment.starts_with(EXIF_HEADER) => {
+                metadata.exif = Some(segment[EXIF_HEADER.len()..].to_vec())
+            }
+            0xE1 if segment.starts_with(XMP_HEADER) => {
+                metadata.xmp = Some(segment[XMP_HEADER.len()..].to_vec())
+            }
+            0xE2 if segment.starts_with(ICC_HEADER) && segment.len() > ICC_HEADER.len() + 2 => {
+                let sequence = segment[ICC_HEADER.len()];
+                icc_chunks.push((sequence, &segment[ICC_HEADER.len() + 2..]));
+            }
+            _ => {}
+        }
+        position += 2 + length;
+    }
+
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(sequence, _)| *sequence);
+        let chunks: Vec<&[u8]> = icc_chunks.into_iter().map(|(_, chunk)| chunk).collect();
+        metadata.icc = Some(chunks.concat());
+    }
+    metadata
+}
+
+fn read_png(data: &[u8], icc_only: bool) -> Metadata {
+    let mut metadata = Metadata::default();
+    let mut position = PNG_SIGNATURE.len();
+
+    while let Some(length) = be_u32(data, position).map(|l| l as usize) {
+        let Some(chunk) = data.get(position + 8..position + 8 + length) else {
+            break;
+        };
+        match &data[position + 4..position + 8] {
+            b"eXIf" if !icc_only => metadata.exif = Some(chunk.to_vec()),
+            b"iCCP" => {
+                // Profile name, compression method, then the zlib stream
+                if let Some(name_end) = chunk.iter().position(|&b| b == 0) {
+                    metadata.icc = chunk.get(name_end + 2..).and_then(inflate);
+                }
+            }
+            b"iTXt" if !icc_only && chunk.starts_with(PNG_XMP_KEYWORD) => {
+                metadata.xmp = read_itxt(&chunk[PNG_XMP_KEYWORD.len()..])
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        // Length, type, data and CRC
+        position += 12 + length;
+    }
+    metadata
+}
+
+// Rest of an iTXt chunk after its keyword: the null separator, compression flag and method,
+// language tag, translated keyword and text
+fn read_itxt(chunk: &[u8]) -> Option<Vec<u8>> {
+    let (&compressed, rest) = chunk.get(1..)?.split_first()?;
+    let rest = rest.get(1..)?;
+    let language_end = rest.iter().position(|&b| b == 0)?;
+    let rest = &rest[language_end + 1..];
+    let translated_end = rest.iter().position(|&b| b == 0)?;
+    let text = &rest[translated_end + 1..];
+    if compressed == 1 {
+        inflate(text)
+    } else {
+        Some(text.to_vec())
+    }
+}
+
+fn read_webp(data: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    for (fourcc, chunk) in webp_chunks(data).unwrap_or_default() {
+        match fourcc {
+            // Some writers keep the JPEG-style prefix
+            b"EXIF" => {
+                metadata.exif = Some(chunk.strip_prefix(EXIF_HEADER).unwrap_or(chunk).to_vec())
+            }
+            b"XMP " => metadata.xmp = Some(chunk.to_vec()),
+            b"ICCP" => metadata.icc = Some(chunk.to_vec()),
+            _ => {}
+        }
+    }
+    metadata
+}
+
+// Splits a WebP RIFF container into (FourCC, payload) chunks
+fn webp_chunks(data: &[u8]) -> Option<Vec<(&[u8; 4], &[u8])>> {
+    if !data.starts_with(b"RIFF") || data.get(8..12) != Some(b"WEBP") {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut position = 12;
+    while position + 8 <= data.len() {
+        let fourcc: &[u8; 4] = data[position..position + 4].try_into().ok()?;
+        let length = u32::from_le_bytes(data[position + 4..position + 8].try_into().ok()?) as usize;
+        chunks.push((fourcc, data.get(position + 8..position + 8 + length)?));
+        // Payloads are padded to an even length
+        position += 8 + length + (length & 1);
+    }
+    Some(chunks)
+}
+
+// Adds a `colr` box of type `prof` with `icc` to the primary item of an AVIF file.
+// The box goes into `ipco` and is associated in `ipma`; `meta` grows, so the `iloc` offsets
+// of the image data behind it move along
+fn embed_avif_icc(encoded: &[u8], icc: &[u8]) -> Option<Vec<u8>> {
+    let mut data = encoded.to_vec();
+    let meta = find_box(&data, 0, data.len(), b"meta")?;
+    // Full box: header plus version and flags
+    let meta_children = meta.0 + 12;
+    let pitm = find_box(&data, meta_children, meta.1, b"pitm")?;
+    let primary_item = if *data.get(pitm.0 + 8)? == 0 {
+        be_u16(&data, pitm.0 + 12)? as u32
+    } else {
+        be_u32(&data, pitm.0 + 12)?
+    };
+    let iloc = find_box(&data, meta_children, meta.1, b"iloc")?;
+    let iprp = find_box(&data, meta_children, meta.1, b"iprp")?;
+    let ipco = find_box(&data, iprp.0 + 8, iprp.1, b"ipco")?;
+    let ipma = find_box(&data, iprp.0 + 8, iprp.1, b"ipma")?;
+
+    let mut colr = ((12 + icc.len()) as u32).to_be_bytes().to_vec();
+    colr.extend_from_slice(b"colrprof");
+    colr.extend_from_slice(icc);
+
+    // Properties are numbered from 1 in the order they appear in ipco
+    let property_index = count_boxes(&data, ipco.0 + 8, ipco.1)? + 1;
+    let ipma_version = *data.get(ipma.0 + 8)?;
+    let large_index = be_u32(&data, ipma.0 + 8)? & 1 == 1;
+    let association = if large_index {
+        (property_index as u16).to_be_bytes().to_vec()
+    } else if property_index < 0x80 {
+        vec![property_index as u8]
+    } else {
+        return None;
+    };
+
+    let mut position = ipma.0 + 16;
+    let mut primary_entry = None;
+    for _ in 0..be_u32(&data, ipma.0 + 12)? {
+        let item = if ipma_version < 1 {
+            position += 2;
+            be_u16(&data, position - 2)? as u32
+        } else {
+            position += 4;
+            be_u32(&data, position - 4)?
+        };
+        let count_position = position;
+        let count = *data.get(count_position)? as usize;
+        position += 1 + count * if large_index { 2 } else { 1 };
+        if item == primary_item {
+            primary_entry = Some((count_position, position));
+        }
+    }
+    let (count_position, association_position) = primary_entry?;
+    data[count_position] = data[count_position].checked_add(1)?;
+
+    let growth = colr.len() + association.len();
+    shift_iloc_offsets(&mut data, iloc, meta.1, growth as u64)?;
+    grow_box(&mut data, meta.0, growth)?;
+    grow_box(&mut data, iprp.0, growth)?;
+    grow_box(&mut data, ipco.0, colr.len())?;
+    grow_box(&mut data, ipma.0, association.len())?;
+
+    // Insert back to front, so the earlier position stays valid
+    let mut inserts = [(ipco.1, colr), (association_position, association)];
+    inserts.sort_by_key(|(position, _)| std::cmp::Reverse(*position));
+    for (position, bytes) in inserts {
+        data.splice(position..position, bytes);
+    }
+    Some(data)
+}
+
+// Moves every file offset in `iloc` that points behind `after` by `growth` bytes
+fn shift_iloc_offsets(
+    data: &mut [u8],
+    iloc: (usize, usize),
+    after: usize,
+    growth: u64,
+) -> Option<()> {
+    let version = *data.get(iloc.0 + 8)?;
+    let mut position = iloc.0 + 12;
+    let offset_size = (*data.get(position)? >> 4) as usize;
+    let length_size = (data[position] & 0x0F) as usize;
+    let base_offset_size = (*data.get(position + 1)? >> 4) as usize;
+    let index_size = if version >= 1 {
+        (data[position + 1] & 0x0F) as usize
+    } else {
+        0
+    };
+    position += 2;
+    let items = if version < 2 {
+        position += 2;
+        be_u16(data, position - 2)? as u32
+    } else {
+        position += 4;
+        be_u32(data, position - 4)?
+    };
+
+    let shift = |data: &mut [u8], at: usize, size: usize| -> Option<()> {
+        let value = read_uint(data, at, size)?;
+        if value >= after as u64 {
+            write_uint(data, at, size, value + growth)?;
+        }
+        Some(())
+    };
+    for _ in 0..items {
+        position += if version < 2 { 2 } else { 4 };
+        // Only construction method 0 (file offsets) points into the file itself
+        let construction_method = if version >= 1 {
+            position += 2;
+            be_u16(data, position - 2)? & 0x0F
+        } else {
+            0
+        };
+        let in_file = construction_method == 0 && be_u16(data, position)? == 0;
+        position += 2;
+        let base_offset = position;
+        position += base_offset_size;
+        let extents = be_u16(data, position)?;
+        position += 2;
+        if in_file && base_offset_size > 0 {
+            shift(data, base_offset, base_offset_size)?;
+        }
+        for _ in 0..extents {
+            position += index_size;
+            if in_file && base_offset_size == 0 {
+                shift(data, position, offset_size)?;
+            }
+            position += offset_size + length_size;
+        }
+    }
+    Some(())
+}
+
+// (start, end) of the first `fourcc` box between `start` and `end`
+fn find_box(data: &[u8], mut start: usize, end: usize, fourcc: &[u8; 4]) -> Option<(usize, usize)> {
+    while start + 8 <= end {
+        let size = box_size(data, start, end)?;
+        if &data[start + 4..start + 8] == fourcc {
+            return Some((start, start + size));
+        }
+        start += size;
+    }
+    None
+}
+
+fn count_boxes(data: &[u8], mut start: usize, end: usize) -> Option<usize> {
+    let mut count = 0;
+    while start + 8 <= end {
+        start += box_size(data, start, end)?;
+        count += 1;
+    }
+    Some(count)
+}
+
+// 64-bit box sizes never occur in the encoder's header boxes, so they are not handled
+fn box_size(data: &[u8], start: usize, end: usize) -> Option<usize> {
+    match be_u32(data, start)? as usize {
+        0 => Some(end - start),
+        size if size >= 8 && start + size <= end => Some(size),
+        _ => None,
+    }
+}
+
+fn grow_box(data: &mut [u8], start: usize, growth: usize) -> Option<()> {
+    let size = be_u32(data, start)?.checked_add(u32::try_from(growth).ok()?)?;
+    data[start..start + 4].copy_from_slice(&size.to_be_bytes());
+    Some(())
+}
+
+fn read_uint(data: &[u8], at: usize, size: usize) -> Option<u64> {
+    let bytes = data.get(at..at + size)?;
+    Some(bytes.iter().fold(0, |value, &b| value << 8 | b as u64))
+}
+
+fn write_uint(data: &mut [u8], at: usize, size: usize, value: u64) -> Option<()> {
+    if size < 8 && value >> (size * 8) != 0 {
+        return None;
+    }
+    let bytes = value.to_be_bytes();
+    data.get_mut(at..at + size)?
+        .copy_from_slice(&bytes[8 - size..]);
+    Some(())
+}
+
+// Rebuilds the EXIF block from the primary image's copyright and orientation only
+fn safe_exif(exif: Vec<u8>) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_raw(exif).ok()?;
+    let fields: Vec<&exif::Field> = [exif::Tag::Copyright, exif::Tag::Orientation]
+        .into_iter()
+        .filter_map(|tag| exif.get_field(tag, exif::In::PRIMARY))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut output = Cursor::new(Vec::new());
+    writer.write(&mut output, exif.little_endian()).ok()?;
+    Some(output.into_inner())
+}
+
+// Rewrites the Orientation entry of IFD0 in place
+fn reset_orientation(tiff: &mut [u8]) {
+    let little_endian = match tiff.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return,
+    };
+    let read_u16 = |tiff: &[u8], at: usize| {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let read_u32 = |tiff: &[u8], at: usize| {
+        let bytes: [u8; 4] = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    let Some(ifd) = read_u32(tiff, 4).map(|offset| offset as usize) else {
+        return;
+    };
+    let entries = read_u16(tiff, ifd).unwrap_or(0) as usize;
+    for entry in (0..entries).map(|i| ifd + 2 + i * 12) {
+        if read_u16(tiff, entry) == Some(TIFF_ORIENTATION) {
+            // SHORT values are stored inline at the start of the value field
+            let value = if little_endian {
+                1u16.to_le_bytes()
+            } else {
+                1u16.to_be_bytes()
+            };
+            if let Some(slot) = tiff.get_mut(entry + 8..entry + 10) {
+                slot.copy_from_slice(&value);
+            }
+            return;
+        }
+    }
+}
+
+fn push_png_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(chunk_type);
+    output.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    output.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+fn push_webp_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+// Anything that inflates past INFLATE_LIMIT is treated as missing
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    ZlibDecoder::new(data)
+        .take(INFLATE_LIMIT as u64 + 1)
+        .read_to_end(&mut output)
+        .ok()?;
+    if output.len() > INFLATE_LIMIT {
+        debug!(
+            "Compressed PNG chunk inflates past {} bytes, ignoring it",
+            INFLATE_LIMIT
+        );
+        return None;
+    }
+    Some(output)
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{Field, In, Tag, Value};
+    use image::{DynamicImage, ImageOutputFormat, RgbaImage};
+    use rgb::FromSlice;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 12, |x, y| {
+            image::Rgba([(x * 16) as u8, (y * 20) as u8, 90, 255])
+        }))
+    }
+
+    fn encode(format: &str) -> Vec<u8> {
+        let img = image();
+        match format {
+            "jpg" | "png" => {
+                let output = match format {
+                    "jpg" => ImageOutputFormat::Jpeg(90),
+                    _ => ImageOutputFormat::Png,
+                };
+                let mut data = Vec::new();
+                img.to_rgb8()
+                    .write_to(&mut Cursor::new(&mut data), output)
+                    .unwrap();
+                data
+            }
+            "webp" => webp::Encoder::from_image(&img)
+                .unwrap()
+                .encode(80.0)
+                .to_vec(),
+            "avif" => {
+                let rgba = img.to_rgba8();
+                ravif::Encoder::new()
+                    .with_speed(10)
+                    .encode_rgba(ravif::Img::new(rgba.as_raw().as_rgba(), 16, 12))
+                    .unwrap()
+                    .avif_file
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // EXIF with a copyright, orientation, camera, serial number and GPS position
+    fn exif_block(orientation: u16) -> Vec<u8> {
+        let ascii = |text: &str| Value::Ascii(vec![text.as_bytes().to_vec()]);
+        let fields = [
+            (Tag::Orientation, Value::Short(vec![orientation])),
+            (Tag::Copyright, ascii("(c) Example")),
+            (Tag::Make, ascii("Camera")),
+            (Tag::BodySerialNumber, ascii("SN-12345")),
+            (Tag::GPSLatitudeRef, ascii("N")),
+        ]
+        .map(|(tag, value)| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        });
+        let mut writer = exif::experimental::Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut output = Cursor::new(Vec::new());
+        writer.write(&mut output, false).unwrap();
+        output.into_inner()
+    }
+
+    fn metadata() -> Metadata {
+        Metadata {
+            exif: Some(exif_block(6)),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>".to_vec()),
+            icc: Some((0..=255).cycle().take(3000).collect()),
+        }
+    }
+
+    fn tags(exif: &[u8]) -> Vec<Tag> {
+        let exif = exif::Reader::new().read_raw(exif.to_vec()).unwrap();
+        exif.fields().map(|field| field.tag).collect()
+    }
+
+    #[test]
+    fn embedded_metadata_reads_back_unchanged() {
+        let metadata = metadata();
+        for format in ["jpg", "png", "webp"] {
+            let output = metadata.embed(format, encode(format), (16, 12)).unwrap();
+
+            let read = read_container(&output, false);
+            assert_eq!(read.exif, metadata.exif, "{}", format);
+            assert_eq!(read.xmp, metadata.xmp, "{}", format);
+            assert_eq!(read.icc, metadata.icc, "{}", format);
+
+            // Other readers find the EXIF and still decode the image
+            let exif = exif::Reader::new()
+                .read_from_container(&mut Cursor::new(&output))
+                .unwrap();
+            let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+            assert_eq!(orientation.value.get_uint(0), Some(6), "{}", format);
+            let (width, height) = match format {
+                "webp" => {
+                    let decoded = webp::Decoder::new(&output).decode().unwrap();
+                    (decoded.width(), decoded.height())
+                }
+                _ => {
+                    let decoded = image::load_from_memory(&output).unwrap();
+                    (decoded.width(), decoded.height())
+                }
+            };
+            assert_eq!((width, height), (16, 12), "{}", format);
+        }
+    }
+
+    #[test]
+    fn large_icc_profile_spans_several_jpeg_segments() {
+        let icc: Vec<u8> = (0..=255).cycle().take(150_000).collect();
+        let metadata = Metadata {
+            icc: Some(icc.clone()),
+            ..Metadata::default()
+        };
+        let output = metadata.embed("jpg", encode("jpg"), (16, 12)).unwrap();
+        assert_eq!(read_icc_profile(&output), Some(icc));
+        assert!(image::load_from_memory(&output).is_ok());
+    }
+
+    #[test]
+    fn policies_pick_what_is_read() {
+        let upload = metadata().embed("jpg", encode("jpg"), (16, 12)).unwrap();
+
+        assert!(Metadata::read(&upload, MetadataPolicy::Strip).is_empty());
+
+        let kept = Metadata::read(&upload, MetadataPolicy::Keep);
+        assert_eq!(kept.exif, metadata().exif);
+        assert_eq!(kept.xmp, metadata().xmp);
+        // The profile follows the color profile mode instead
+        assert_eq!(kept.icc, None);
+    }
+
+    #[test]
+    fn safe_mode_keeps_only_copyright_and_orientation() {
+        let upload = metadata().embed("png", encode("png"), (16, 12)).unwrap();
+        let mut safe = Metadata::read(&upload, MetadataPolicy::Safe);
+        assert!(safe.xmp.is_none() && safe.icc.is_none());
+
+        let mut kept = tags(safe.exif.as_ref().unwrap());
+        kept.sort_by_key(|tag| tag.number());
+        assert_eq!(kept, [Tag::Orientation, Tag::Copyright]);
+
+        safe.mark_upright();
+        let exif = exif::Reader::new().read_raw(safe.exif.unwrap()).unwrap();
+        let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(1));
+        let copyright = exif.get_field(Tag::Copyright, In::PRIMARY).unwrap();
+        assert_eq!(copyright.display_value().to_string(), "\"(c) Example\"");
+    }
+
+    // Bytes each `iloc` extent points at, read as the spec lays the box out
+    fn item_data(data: &[u8]) -> Vec<Vec<u8>> {
+        let meta = find_box(data, 0, data.len(), b"meta").unwrap();
+        let iloc = find_box(data, meta.0 + 12, meta.1, b"iloc").unwrap();
+        let version = data[iloc.0 + 8];
+        let mut position = iloc.0 + 12;
+        let offset_size = (data[position] >> 4) as usize;
+        let length_size = (data[position] & 0x0F) as usize;
+        let base_offset_size = (data[position + 1] >> 4) as usize;
+        let index_size = if version >= 1 {
+            (data[position + 1] & 0x0F) as usize
+        } else {
+            0
+        };
+        position += 2;
+        let id_size = if version < 2 { 2 } else { 4 };
+        let items = read_uint(data, position, id_size).unwrap();
+        position += id_size;
+
+        let mut extents = Vec::new();
+        for _ in 0..items {
+            position += id_size + if version >= 1 { 2 } else { 0 } + 2;
+            let base_offset = read_uint(data, position, base_offset_size).unwrap();
+            position += base_offset_size;
+            let count = be_u16(data, position).unwrap();
+            position += 2;
+            for _ in 0..count {
+                position += index_size;
+                let offset = read_uint(data, position, offset_size).unwrap();
+                position += offset_size;
+                let length = read_uint(data, position, length_size).unwrap();
+                position += length_size;
+                let start = (base_offset + offset) as usize;
+                extents.push(data[start..start + length as usize].to_vec());
+            }
+        }
+        extents
+    }
+
+    #[test]
+    fn avif_icc_keeps_the_item_data_in_place() {
+        let encoded = encode("avif");
+        let icc: Vec<u8> = (0..=255).cycle().take(600).collect();
+        let metadata = Metadata {
+            icc: Some(icc.clone()),
+            ..metadata()
+        };
+        let output = metadata.embed("avif", encoded.clone(), (16, 12)).unwrap();
+
+        assert_eq!(output.len(), encoded.len() + 12 + icc.len() + 1);
+        let before = item_data(&encoded);
+        assert!(!before.is_empty());
+        assert_eq!(item_data(&output), before);
+
+        let meta = find_box(&output, 0, output.len(), b"meta").unwrap();
+        let iprp = find_box(&output, meta.0 + 12, meta.1, b"iprp").unwrap();
+        let ipco = find_box(&output, iprp.0 + 8, iprp.1, b"ipco").unwrap();
+        let colr = find_box(&output, ipco.0 + 8, ipco.1, b"colr").unwrap();
+        assert_eq!(&output[colr.0 + 8..colr.0 + 12], b"prof");
+        assert_eq!(&output[colr.0 + 12..colr.1], &icc[..]);
+    }
+
+    #[test]
+    fn malformed_output_is_an_error() {
+        for format in ["jpg", "png", "webp", "avif"] {
+            let err = metadata()
+                .embed(format, b"not an image".to_vec(), (16, 12))
+                .unwrap_err();
+            assert_eq!(err.code(), "encode_failed", "{}", format);
+        }
+        let truncated = encode("png")[..20].to_vec();
+        assert!(metadata().embed("png", truncated, (16, 12)).is_err());
+    }
+
+    #[test]
+    fn chunks_inflating_past_the_limit_are_ignored() {
+        let oversized = vec![0; INFLATE_LIMIT + 1];
+        let icc = Metadata {
+            icc: Some(oversized.clone()),
+            ..Metadata::default()
+        }
+        .embed("png", encode("png"), (16, 12))
+        .unwrap();
+        assert!(icc.len() < 64 * 1024, "{} bytes", icc.len());
+        assert_eq!(read_icc_profile(&icc), None);
+
+        // Compressed XMP: keyword, compression flag and method, empty language and keyword
+        let mut itxt = PNG_XMP_KEYWORD.to_vec();
+        itxt.extend_from_slice(&[0, 1, 0, 0, 0]);
+        let mut encoder = ZlibEncoder::new(&mut itxt, Compression::default());
+        encoder.write_all(&oversized).unwrap();
+        encoder.finish().unwrap();
+        let mut chunk = Vec::new();
+        push_png_chunk(&mut chunk, b"iTXt", &itxt);
+        let mut xmp = encode("png");
+        xmp.splice(PNG_SIGNATURE.len() + 25..PNG_SIGNATURE.len() + 25, chunk);
+        assert_eq!(Metadata::read(&xmp, MetadataPolicy::Keep).xmp, None);
+    }
+
+    #[test]
+    fn profile_lookup_leaves_png_text_chunks_unparsed() {
+        let metadata = metadata();
+        let upload = metadata.embed("png", encode("png"), (16, 12)).unwrap();
+        let read = read_container(&upload, true);
+        assert_eq!(read.icc, metadata.icc);
+        assert_eq!((read.exif, read.xmp), (None, None));
+        assert_eq!(read_icc_profile(&upload), metadata.icc);
+    }
+
+    #[test]
+    fn truncated_files_do_not_panic() {
+        for format in ["jpg", "png", "webp"] {
+            let upload = metadata().embed(format, encode(format), (16, 12)).unwrap();
+            for end in 0..upload.len() {
+                let mut read = Metadata::read(&upload[..end], MetadataPolicy::Safe);
+                read.mark_upright();
+                read_icc_profile(&upload[..end]);
+            }
+        }
+
+        let avif = encode("avif");
+        for end in 0..avif.len() {
+            let _ = metadata().embed("avif", avif[..end].to_vec(), (16, 12));
+        }
+        let mut exif = exif_block(6);
+        for end in 0..exif.len() {
+            reset_orientation(&mut exif[..end]);
+        }
+    }
+}
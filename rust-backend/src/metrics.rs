@@ -0,0 +1,165 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// Process-wide conversion metrics, served as Prometheus text on `GET /metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Batches by target format.
+    pub jobs_started: IntCounterVec,
+    pub jobs_completed: IntCounterVec,
+    pub jobs_failed: IntCounterVec,
+    /// Single files by target format and `ok` / `failed` / `canceled`.
+    pub files: IntCounterVec,
+    pub decode_seconds: Histogram,
+    pub encode_seconds: HistogramVec,
+    pub input_bytes: IntCounterVec,
+    pub output_bytes: IntCounterVec,
+    /// Output size divided by input size, per converted file.
+    pub compression_ratio: HistogramVec,
+    /// Files waiting for a worker slot or memory in `process_images`.
+    pub queue_depth: IntGauge,
+    /// Estimated bytes reserved from the global memory budget.
+    pub memory_reserved_bytes: IntGauge,
+    /// Connected progress WebSockets; the server sets it on every scrape.
+    pub websocket_sessions: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("convertxion".to_string()), None)
+            .expect("valid metrics prefix");
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("valid counter");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("unique metric name");
+            counter
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str], buckets: Vec<f64>| {
+            let histogram =
+                HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels)
+                    .expect("valid histogram");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("unique metric name");
+            histogram
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGauge::new(name, help).expect("valid gauge");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("unique metric name");
+            gauge
+        };
+        let seconds = vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+        ];
+
+        Metrics {
+            jobs_started: counter("jobs_started_total", "Batches started", &["format"]),
+            jobs_completed: counter("jobs_completed_total", "Batches completed", &["format"]),
+            jobs_failed: counter(
+                "jobs_failed_total",
+                "Batches failed or canceled",
+                &["format"],
+            ),
+            files: counter(
+                "files_total",
+                "Files processed by outcome",
+                &["format", "status"],
+            ),
+            decode_seconds: {
+                let histogram = Histogram::with_opts(
+                    HistogramOpts::new("decode_seconds", "Time spent decoding uploads")
+                        .buckets(seconds.clone()),
+                )
+                .expect("valid histogram");
+                registry
+                    .register(Box::new(histogram.clone()))
+                    .expect("unique metric name");
+                histogram
+            },
+            encode_seconds: histogram(
+                "encode_seconds",
+                "Time spent encoding, by output format (every candidate for format=auto)",
+                &["format"],
+                seconds,
+            ),
+            input_bytes: counter(
+                "input_bytes_total",
+                "Bytes of converted uploads",
+                &["format"],
+            ),
+            output_bytes: counter(
+                "output_bytes_total",
+                "Bytes of converted output",
+                &["format"],
+            ),
+            compression_ratio: histogram(
+                "compression_ratio",
+                "Output size divided by input size",
+                &["format"],
+                vec![0.05, 0.1, 0.2, 0.3, 0.5, 0.75, 1.0, 1.5, 2.0, 5.0],
+            ),
+            queue_depth: gauge("queue_depth", "Files waiting for a worker slot or memory"),
+            memory_reserved_bytes: gauge(
+                "memory_reserved_bytes",
+                "Estimated bytes reserved from the memory budget",
+            ),
+            websocket_sessions: gauge("websocket_sessions", "Connected progress WebSockets"),
+            registry,
+        }
+    }
+
+    /// Encodes every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_uses_the_prefix_and_labels() {
+        let metrics = Metrics::new();
+        metrics.jobs_started.with_label_values(&["webp"]).inc();
+        metrics
+            .files
+            .with_label_values(&["webp", "failed"])
+            .inc_by(2);
+        metrics.queue_depth.set(3);
+
+        let text = metrics.render();
+        assert!(text.contains("convertxion_jobs_started_total{format=\"webp\"} 1"));
+        assert!(text.contains("convertxion_files_total{format=\"webp\",status=\"failed\"} 2"));
+        assert!(text.contains("convertxion_queue_depth 3"));
+        assert!(text.contains("# TYPE convertxion_websocket_sessions gauge"));
+    }
+
+    #[test]
+    fn histograms_are_rendered_with_buckets() {
+        let metrics = Metrics::new();
+        metrics.decode_seconds.observe(0.02);
+        metrics
+            .compression_ratio
+            .with_label_values(&["avif"])
+            .observe(0.4);
+
+        let text = metrics.render();
+        assert!(text.contains("convertxion_decode_seconds_bucket{le=\"0.025\"} 1"));
+        assert!(text.contains("convertxion_decode_seconds_bucket{le=\"0.01\"} 0"));
+        assert!(text.contains("convertxion_decode_seconds_count 1"));
+        assert!(text.contains("convertxion_compression_ratio_bucket{format=\"avif\",le=\"0.5\"} 1"));
+    }
+}
@@ -1,4 +0,0 @@
-pub mod handlers;
-pub mod image_utils;
-pub mod routes;
-pub mod ws_handler;
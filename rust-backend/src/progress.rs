@@ -0,0 +1,264 @@
+use crate::error::EngineError;
+use crate::image_utils::FileResult;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/// Bumped whenever the shape of [`ProgressEvent`] changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Overall percentage milestones, shared by the handler and the batch pipeline
+pub const PROGRESS_VALIDATED: f32 = 10.0;
+pub const PROGRESS_ARCHIVING: f32 = 90.0;
+pub const PROGRESS_DONE: f32 = 100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Uploading,
+    Validated,
+    Decoding,
+    Encoding,
+    Archiving,
+    Done,
+    Error,
+}
+
+/// Per-file part of a [`ProgressEvent`]. `result` is set once the file is finished.
+#[derive(Debug, Serialize)]
+pub struct FileProgress<'a> {
+    pub index: usize,
+    pub total: usize,
+    pub name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<&'a FileResult>,
+}
+
+/// One JSON frame on the progress WebSocket.
+#[derive(Debug, Serialize)]
+pub struct ProgressEvent<'a> {
+    pub version: u32,
+    pub stage: Stage,
+    /// Overall percentage; never goes down within a session.
+    pub progress: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileProgress<'a>>,
+}
+
+/// Reply to a text command received on the progress WebSocket.
+#[derive(Debug, Serialize)]
+pub struct CommandAck<'a> {
+    pub version: u32,
+    /// The command being acknowledged, e.g. `cancel` or `cancel_file`.
+    pub ack: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<&'a str>,
+    /// False when the session had no running conversion.
+    pub accepted: bool,
+}
+
+/// Receives the progress events of a batch.
+/// The server forwards them to the session's WebSocket; closures work too.
+pub trait ProgressSink: Send + Sync {
+    fn send(&self, event: &ProgressEvent);
+}
+
+impl<F> ProgressSink for F
+where
+    F: Fn(&ProgressEvent) + Send + Sync,
+{
+    fn send(&self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+/// Builds [`ProgressEvent`]s for one batch and hands them to a [`ProgressSink`].
+#[derive(Clone)]
+pub struct ProgressReporter {
+    id: Arc<String>,
+    sink: Arc<dyn ProgressSink>,
+    // Highest percentage sent so far
+    last_progress: Arc<Mutex<f32>>,
+}
+
+impl ProgressReporter {
+    /// `id` names the batch in logs; the server uses the session ID.
+    pub fn new(id: impl Into<String>, sink: impl ProgressSink + 'static) -> Self {
+        ProgressReporter {
+            id: Arc::new(id.into()),
+            sink: Arc::new(sink),
+            last_progress: Arc::new(Mutex::new(0.0)),
+        }
+    }
+
+    /// A reporter that drops every event.
+    pub fn disabled(id: impl Into<String>) -> Self {
+        Self::new(id, |_: &ProgressEvent| {})
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Reports a batch-level stage change.
+    pub fn stage(&self, stage: Stage, progress: f32, message: impl Into<String>) {
+        self.send(ProgressEvent {
+            version: PROTOCOL_VERSION,
+            stage,
+            progress,
+            filename: None,
+            message: Some(message.into()),
+            code: None,
+            file: None,
+        });
+    }
+
+    /// Reports a per-file event. Pass `None` to keep the current overall percentage.
+    pub fn file(&self, stage: Stage, progress: Option<f32>, file: FileProgress) {
+        self.send(ProgressEvent {
+            version: PROTOCOL_VERSION,
+            stage,
+            progress: progress.unwrap_or(0.0),
+            filename: Some(file.name),
+            message: None,
+            code: None,
+            file: Some(file),
+        });
+    }
+
+    /// Reports a failed request. The percentage is left where it was.
+    pub fn error(&self, err: &EngineError) {
+        self.send(ProgressEvent {
+            version: PROTOCOL_VERSION,
+            stage: Stage::Error,
+            progress: 0.0,
+            filename: None,
+            message: Some(err.to_string()),
+            code: Some(err.code()),
+            file: None,
+        });
+    }
+
+    fn send(&self, mut event: ProgressEvent) {
+        // Hold the lock while sending so frames leave in percentage order
+        let mut last = self.last_progress.lock().unwrap();
+        event.progress = ((event.progress.max(*last)) * 100.0).round() / 100.0;
+        *last = event.progress;
+
+        self.sink.send(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reporter that records every event as JSON
+    fn recording() -> (ProgressReporter, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let reporter = ProgressReporter::new("test", move |event: &ProgressEvent| {
+            sink.lock()
+                .unwrap()
+                .push(serde_json::to_value(event).unwrap());
+        });
+        (reporter, events)
+    }
+
+    #[test]
+    fn stage_event_is_versioned_and_leaves_out_empty_fields() {
+        let (reporter, events) = recording();
+        reporter.stage(Stage::Validated, PROGRESS_VALIDATED, "Files validated");
+        assert_eq!(
+            events.lock().unwrap()[0],
+            serde_json::json!({
+                "version": PROTOCOL_VERSION,
+                "stage": "validated",
+                "progress": 10.0,
+                "message": "Files validated",
+            })
+        );
+    }
+
+    #[test]
+    fn file_event_carries_the_file() {
+        let (reporter, events) = recording();
+        reporter.file(
+            Stage::Decoding,
+            None,
+            FileProgress {
+                index: 1,
+                total: 3,
+                name: "b.png",
+                result: None,
+            },
+        );
+        let event = &events.lock().unwrap()[0];
+        assert_eq!(event["stage"], "decoding");
+        assert_eq!(event["filename"], "b.png");
+        assert_eq!(
+            event["file"],
+            serde_json::json!({ "index": 1, "total": 3, "name": "b.png" })
+        );
+    }
+
+    #[test]
+    fn progress_never_goes_down_and_is_rounded() {
+        let (reporter, events) = recording();
+        reporter.stage(Stage::Encoding, 36.666_67, "a");
+        reporter.stage(Stage::Encoding, 20.0, "b");
+        reporter.error(&EngineError::Canceled);
+
+        let events = events.lock().unwrap();
+        let progress: Vec<f32> = events
+            .iter()
+            .map(|e| e["progress"].as_f64().unwrap() as f32)
+            .collect();
+        assert_eq!(progress, [36.67, 36.67, 36.67]);
+        assert_eq!(events[2]["stage"], "error");
+        assert_eq!(events[2]["code"], "canceled");
+    }
+
+    #[test]
+    fn custom_sinks_receive_events_from_every_clone() {
+        struct Counter(Arc<Mutex<usize>>);
+        impl ProgressSink for Counter {
+            fn send(&self, _event: &ProgressEvent) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let count = Arc::new(Mutex::new(0));
+        let reporter = ProgressReporter::new("batch", Counter(count.clone()));
+        let clone = reporter.clone();
+        reporter.stage(Stage::Decoding, 10.0, "a");
+        clone.stage(Stage::Encoding, 20.0, "b");
+
+        assert_eq!(*count.lock().unwrap(), 2);
+        assert_eq!(clone.id(), "batch");
+    }
+
+    #[test]
+    fn command_ack_names_the_command() {
+        let ack = CommandAck {
+            version: PROTOCOL_VERSION,
+            ack: "cancel_file",
+            filename: Some("a.png"),
+            accepted: false,
+        };
+        assert_eq!(
+            serde_json::to_value(&ack).unwrap(),
+            serde_json::json!({
+                "version": PROTOCOL_VERSION,
+                "ack": "cancel_file",
+                "filename": "a.png",
+                "accepted": false,
+            })
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use crate::error::EngineError;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How an image is fitted into the requested resolution, after CSS `object-fit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMode {
+    /// Stretch to exactly the requested size.
+    #[default]
+    Fill,
+    /// Scale to fit inside the requested size, keeping the aspect ratio.
+    Contain,
+    /// Scale to cover the requested size, then crop the overflow at the gravity.
+    Cover,
+    /// Scale like `contain`, then letterbox to exactly the requested size.
+    Pad,
+    /// Like `contain`, but images that already fit are never enlarged.
+    Inside,
+}
+
+impl FromStr for ResizeMode {
+    type Err = EngineError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fill" => Ok(ResizeMode::Fill),
+            "contain" => Ok(ResizeMode::Contain),
+            "cover" => Ok(ResizeMode::Cover),
+            "pad" => Ok(ResizeMode::Pad),
+            "inside" => Ok(ResizeMode::Inside),
+            other => Err(EngineError::InvalidRequest(format!(
+                "Unknown resize mode '{}' (expected fill, contain, cover, pad or inside)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Which part of the image `cover` keeps and where `pad` places it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+    Northeast,
+    Northwest,
+    Southeast,
+    Southwest,
+}
+
+impl Gravity {
+    // Position along (x, y) in halves: 0 is the start, 1 the middle, 2 the end
+    fn halves(self) -> (u32, u32) {
+        match self {
+            Gravity::Center => (1, 1),
+            Gravity::North => (1, 0),
+            Gravity::South => (1, 2),
+            Gravity::East => (2, 1),
+            Gravity::West => (0, 1),
+            Gravity::Northeast => (2, 0),
+            Gravity::Northwest => (0, 0),
+            Gravity::Southeast => (2, 2),
+            Gravity::Southwest => (0, 2),
+        }
+    }
+
+    // Offset of a `size` box placed inside (pad) or cut out of (cover) an `outer` box
+    pub(crate) fn offset(self, outer: (u32, u32), size: (u32, u32)) -> (u32, u32) {
+        let (x, y) = self.halves();
+        (
+            (outer.0.abs_diff(size.0) as u64 * x as u64 / 2) as u32,
+            (outer.1.abs_diff(size.1) as u64 * y as u64 / 2) as u32,
+        )
+    }
+}
+
+impl FromStr for Gravity {
+    type Err = EngineError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "center" => Ok(Gravity::Center),
+            "north" => Ok(Gravity::North),
+            "south" => Ok(Gravity::South),
+            "east" => Ok(Gravity::East),
+            "west" => Ok(Gravity::West),
+            "northeast" => Ok(Gravity::Northeast),
+            "northwest" => Ok(Gravity::Northwest),
+            "southeast" => Ok(Gravity::Southeast),
+            "southwest" => Ok(Gravity::Southwest),
+            other => Err(EngineError::InvalidRequest(format!(
+                "Unknown gravity '{}' (expected center, north, south, east, west, \
+                 northeast, northwest, southeast or southwest)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Background used by `pad` unless the request sets one.
+pub const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// Color names `parse_color` accepts besides hex values
+const NAMED_COLORS: [(&str, [u8; 4]); 7] = [
+    ("white", [255, 255, 255, 255]),
+    ("black", [0, 0, 0, 255]),
+    ("transparent", [0, 0, 0, 0]),
+    ("gray", [128, 128, 128, 255]),
+    ("red", [255, 0, 0, 255]),
+    ("green", [0, 128, 0, 255]),
+    ("blue", [0, 0, 255, 255]),
+];
+
+/// Parses a hex color, `RRGGBB` or `RRGGBBAA`, with or without a leading `#`, or one of
+/// `white`, `black`, `transparent`, `gray`, `red`, `green` and `blue`.
+pub fn parse_color(value: &str) -> Result<Rgba<u8>, EngineError> {
+    let invalid = || {
+        EngineError::InvalidRequest(format!(
+            "Invalid background color '{}' (expected #RRGGBB, #RRGGBBAA or a color name)",
+            value
+        ))
+    };
+    if let Some((_, color)) = NAMED_COLORS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+    {
+        return Ok(Rgba(*color));
+    }
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut color = [0, 0, 0, 255];
+    for (channel, i) in color.iter_mut().zip((0..hex.len()).step_by(2)) {
+        *channel = u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(Rgba(color))
+}
+
+/// Resizes `img` to the `target` resolution as `mode` describes.
+pub fn resize(
+    img: DynamicImage,
+    target: (u32, u32),
+    mode: ResizeMode,
+    gravity: Gravity,
+    background: Rgba<u8>,
+) -> DynamicImage {
+    let (width, height) = target;
+    match mode {
+        ResizeMode::Fill => img.resize_exact(width, height, FilterType::Lanczos3),
+        ResizeMode::Contain => img.resize(width, height, FilterType::Lanczos3),
+        ResizeMode::Inside => {
+            let (orig_width, orig_height) = img.dimensions();
+            if orig_width <= width && orig_height <= height {
+                img
+            } else {
+                img.resize(width, height, FilterType::Lanczos3)
+            }
+        }
+        ResizeMode::Cover => {
+            let scaled = scaled_size(img.dimensions(), target, mode);
+            let img = img.resize_exact(scaled.0, scaled.1, FilterType::Lanczos3);
+            let (x, y) = gravity.offset(scaled, target);
+            img.crop_imm(x, y, width, height)
+        }
+        ResizeMode::Pad => {
+            let opaque = background[3] == u8::MAX && !img.color().has_alpha();
+            let img = img.resize(width, height, FilterType::Lanczos3);
+            let (x, y) = gravity.offset(target, img.dimensions());
+            let mut canvas = RgbaImage::from_pixel(width, height, background);
+            imageops::overlay(&mut canvas, &img.to_rgba8(), x as i64, y as i64);
+            canvas_image(canvas, opaque)
+        }
+    }
+}
+
+// Drops the alpha channel of a filled canvas again when neither the input nor the fill had one
+pub(crate) fn canvas_image(canvas: RgbaImage, opaque: bool) -> DynamicImage {
+    if opaque {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).into_rgb8())
+    } else {
+        DynamicImage::ImageRgba8(canvas)
+    }
+}
+
+/// Size of the scaled image before `cover` crops it or `pad` centers it on the canvas.
+pub fn scaled_size(source: (u32, u32), target: (u32, u32), mode: ResizeMode) -> (u32, u32) {
+    let (width, height) = (source.0.max(1) as f64, source.1.max(1) as f64);
+    let scale_x = target.0 as f64 / width;
+    let scale_y = target.1 as f64 / height;
+    let scale = match mode {
+        ResizeMode::Fill => return target,
+        ResizeMode::Cover => scale_x.max(scale_y),
+        ResizeMode::Inside if source.0 <= target.0 && source.1 <= target.1 => return source,
+        ResizeMode::Contain | ResizeMode::Pad | ResizeMode::Inside => scale_x.min(scale_y),
+    };
+    let scaled = (
+        ((width * scale).round() as u32).max(1),
+        ((height * scale).round() as u32).max(1),
+    );
+    match mode {
+        // Rounding must not leave the cover image short of the target
+        ResizeMode::Cover => (scaled.0.max(target.0), scaled.1.max(target.1)),
+        _ => (scaled.0.min(target.0), scaled.1.min(target.1)),
+    }
+}
+
+/// Pixels held at once while resizing: the scaled image, plus the crop or canvas for
+/// `cover` and `pad`.
+pub fn peak_pixels(source: (u32, u32), target: (u32, u32), mode: ResizeMode) -> u64 {
+    let (width, height) = scaled_size(source, target, mode);
+    let scaled = width as u64 * height as u64;
+    match mode {
+        ResizeMode::Cover | ResizeMode::Pad => scaled + target.0 as u64 * target.1 as u64,
+        _ => scaled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_are_hex_or_named() {
+        assert_eq!(parse_color("#ff8000").unwrap(), Rgba([255, 128, 0, 255]));
+        assert_eq!(parse_color("FF800080").unwrap(), Rgba([255, 128, 0, 128]));
+        assert_eq!(parse_color("black").unwrap(), Rgba([0, 0, 0, 255]));
+        assert_eq!(parse_color("Transparent").unwrap(), Rgba([0, 0, 0, 0]));
+        for value in ["", "#fff", "#ff80001", "ff80zz", "#ff80é", "purple-ish"] {
+            let err = parse_color(value).unwrap_err();
+            assert_eq!(err.code(), "invalid_request", "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn scaled_sizes_follow_the_mode() {
+        let landscape = (400, 200);
+        let portrait = (200, 400);
+        let target = (100, 100);
+        for (mode, landscape_size, portrait_size) in [
+            (ResizeMode::Fill, (100, 100), (100, 100)),
+            (ResizeMode::Contain, (100, 50), (50, 100)),
+            (ResizeMode::Pad, (100, 50), (50, 100)),
+            (ResizeMode::Inside, (100, 50), (50, 100)),
+            (ResizeMode::Cover, (200, 100), (100, 200)),
+        ] {
+            assert_eq!(
+                scaled_size(landscape, target, mode),
+                landscape_size,
+                "{:?}",
+                mode
+            );
+            assert_eq!(
+                scaled_size(portrait, target, mode),
+                portrait_size,
+                "{:?}",
+                mode
+            );
+        }
+        // Only inside keeps small images as they are
+        assert_eq!(scaled_size((40, 20), target, ResizeMode::Inside), (40, 20));
+        assert_eq!(
+            scaled_size((40, 20), target, ResizeMode::Contain),
+            (100, 50)
+        );
+        // Extreme ratios never reach zero
+        assert_eq!(
+            scaled_size((10_000, 1), target, ResizeMode::Contain),
+            (100, 1)
+        );
+    }
+
+    #[test]
+    fn resize_produces_the_scaled_or_target_size() {
+        let img = DynamicImage::new_rgb8(400, 200);
+        let background = DEFAULT_BACKGROUND;
+        let size =
+            |mode| resize(img.clone(), (100, 100), mode, Gravity::Center, background).dimensions();
+        assert_eq!(size(ResizeMode::Fill), (100, 100));
+        assert_eq!(size(ResizeMode::Contain), (100, 50));
+        assert_eq!(size(ResizeMode::Inside), (100, 50));
+        assert_eq!(size(ResizeMode::Cover), (100, 100));
+        assert_eq!(size(ResizeMode::Pad), (100, 100));
+    }
+
+    #[test]
+    fn pad_letterboxes_at_the_gravity() {
+        let img =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(40, 20, image::Rgb([0, 0, 255])));
+        let black = Rgba([0, 0, 0, 255]);
+        let padded = resize(
+            img.clone(),
+            (40, 40),
+            ResizeMode::Pad,
+            Gravity::North,
+            black,
+        );
+        // Opaque input on an opaque fill stays RGB
+        assert_eq!(padded.color(), image::ColorType::Rgb8);
+        let padded = padded.to_rgb8();
+        assert_eq!(padded.get_pixel(20, 5).0, [0, 0, 255]);
+        assert_eq!(padded.get_pixel(20, 35).0, [0, 0, 0]);
+
+        let see_through = resize(
+            img,
+            (40, 40),
+            ResizeMode::Pad,
+            Gravity::Center,
+            Rgba([0, 0, 0, 0]),
+        );
+        assert!(see_through.color().has_alpha());
+        assert_eq!(see_through.to_rgba8().get_pixel(20, 2).0[3], 0);
+    }
+
+    #[test]
+    fn gravity_offsets_the_box() {
+        assert_eq!(Gravity::Center.offset((100, 50), (40, 20)), (30, 15));
+        assert_eq!(Gravity::Northwest.offset((100, 50), (40, 20)), (0, 0));
+        assert_eq!(Gravity::Southeast.offset((100, 50), (40, 20)), (60, 30));
+        assert!("middle".parse::<Gravity>().is_err());
+    }
+}
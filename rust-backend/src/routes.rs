@@ -1,4 +1,7 @@
-use crate::handlers::{cancel_conversion, convert_image, health_check};
+use crate::handlers::{
+    cancel_conversion, convert_image, create_job, create_session, health_check, job_result,
+    job_status, metrics,
+};
 use crate::ws_handler::ws_route;
 use actix_web::web; // Import the cancel_conversion handler
 
@@ -6,8 +9,13 @@ pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api") // Group routes under `/api`
             .route("/health", web::get().to(health_check)) // Health check endpoint
+            .route("/sessions", web::post().to(create_session)) // Issue a session ID
             .route("/convert", web::post().to(convert_image)) // Image conversion endpoint
             .route("/cancel", web::post().to(cancel_conversion)) // Cancel conversion endpoint
+            .route("/jobs", web::post().to(create_job)) // Async conversion job
+            .route("/jobs/{id}", web::get().to(job_status)) // Job status
+            .route("/jobs/{id}/result", web::get().to(job_result)) // Download finished ZIP
             .route("/ws", web::get().to(ws_route)),
-    );
+    )
+    .route("/metrics", web::get().to(metrics)); // Prometheus metrics
 }
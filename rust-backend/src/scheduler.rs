@@ -0,0 +1,282 @@
+use crate::error::EngineError;
+use crate::image_utils::CancelToken;
+use crate::metrics::METRICS;
+use log::info;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+
+/// One memory budget shared by every conversion in the process.
+///
+/// Each file reserves its estimated peak memory before it is decoded and waits while the
+/// budget is used up. Waiting files are queued per session and sessions take turns, one file
+/// each, so a large batch cannot starve the others.
+pub struct MemoryScheduler {
+    budget: u64,
+    state: Mutex<SchedulerState>,
+}
+
+struct SchedulerState {
+    available: u64,
+    // Sessions with waiting files, in turn order; a session leaves once its queue is empty
+    queues: VecDeque<(String, VecDeque<Waiter>)>,
+}
+
+struct Waiter {
+    bytes: u64,
+    wake: oneshot::Sender<MemoryReservation>,
+}
+
+/// Memory held by one file; it goes back to the budget on drop.
+pub struct MemoryReservation {
+    scheduler: Arc<MemoryScheduler>,
+    bytes: u64,
+}
+
+impl MemoryScheduler {
+    pub fn new(budget: u64) -> Self {
+        MemoryScheduler {
+            budget,
+            state: Mutex::new(SchedulerState {
+                available: budget,
+                queues: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// Waits until `bytes` fit into the budget, taking turns with the other sessions.
+    /// Fails right away when `bytes` exceed the whole budget, and with `Canceled` as soon as
+    /// `cancel` drops the file while it waits.
+    pub async fn reserve(
+        self: &Arc<Self>,
+        session_id: &str,
+        filename: &str,
+        bytes: u64,
+        cancel: &CancelToken,
+    ) -> Result<MemoryReservation, EngineError> {
+        if bytes > self.budget {
+            return Err(EngineError::LimitExceeded(format!(
+                "'{}' needs about {} MB to convert, more than the {} MB memory budget",
+                filename,
+                bytes.div_ceil(1024 * 1024),
+                self.budget / (1024 * 1024)
+            )));
+        }
+
+        let wake = {
+            let mut state = self.state.lock().unwrap();
+            // Skip the queue only when nobody is waiting, so waiting files are never overtaken
+            if state.queues.is_empty() && state.available >= bytes {
+                state.available -= bytes;
+                METRICS.memory_reserved_bytes.add(bytes as i64);
+                return Ok(MemoryReservation {
+                    scheduler: self.clone(),
+                    bytes,
+                });
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let waiter = Waiter { bytes, wake: tx };
+            match state.queues.iter_mut().find(|(id, _)| id == session_id) {
+                Some((_, waiters)) => waiters.push_back(waiter),
+                None => state
+                    .queues
+                    .push_back((session_id.to_string(), VecDeque::from([waiter]))),
+            }
+            rx
+        };
+
+        tokio::select! {
+            reservation = wake => reservation.map_err(|_| {
+                EngineError::Internal("Memory scheduler dropped a waiting file".into())
+            }),
+            _ = cancel.file_canceled(filename) => {
+                // `wake` is dropped by now; memory granted in the meantime came back with it
+                info!("⏹️ '{}' canceled while waiting for memory", filename);
+                self.forget_canceled();
+                Err(EngineError::Canceled)
+            }
+        }
+    }
+
+    // Returns `bytes` to the budget and hands it on to the waiting files
+    fn release(self: &Arc<Self>, bytes: u64) {
+        METRICS.memory_reserved_bytes.sub(bytes as i64);
+        let mut state = self.state.lock().unwrap();
+        state.available += bytes;
+        self.hand_out(state);
+    }
+
+    // Drops the waiters whose task stopped waiting; the files behind them may fit now
+    fn forget_canceled(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        for (_, waiters) in state.queues.iter_mut() {
+            waiters.retain(|waiter| !waiter.wake.is_closed());
+        }
+        state.queues.retain(|(_, waiters)| !waiters.is_empty());
+        self.hand_out(state);
+    }
+
+    // Gives the available memory to waiting files, taking the lock guard so callers update
+    // the state and hand out in one step
+    fn hand_out(self: &Arc<Self>, mut guard: MutexGuard<'_, SchedulerState>) {
+        let mut unclaimed = Vec::new();
+        {
+            let state = &mut *guard;
+
+            // One file per session and turn. Stop at the first file that does not fit, so a
+            // large image is not passed over forever by smaller ones
+            while let Some((_, waiters)) = state.queues.front_mut() {
+                if waiters.front().is_none_or(|w| w.bytes > state.available) {
+                    break;
+                }
+                let waiter = waiters.pop_front().expect("checked above");
+                let turn = state.queues.pop_front().expect("checked above");
+                if !turn.1.is_empty() {
+                    state.queues.push_back(turn);
+                }
+
+                state.available -= waiter.bytes;
+                METRICS.memory_reserved_bytes.add(waiter.bytes as i64);
+                let reservation = MemoryReservation {
+                    scheduler: self.clone(),
+                    bytes: waiter.bytes,
+                };
+                // The waiting task is gone (e.g. aborted); its share is released below
+                if let Err(reservation) = waiter.wake.send(reservation) {
+                    unclaimed.push(reservation);
+                }
+            }
+        }
+        drop(guard);
+        // Dropped outside the lock, since dropping a reservation releases it again
+        drop(unclaimed);
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.scheduler.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn queued(scheduler: &MemoryScheduler) -> usize {
+        let state = scheduler.state.lock().unwrap();
+        state.queues.iter().map(|(_, waiters)| waiters.len()).sum()
+    }
+
+    // Starts a reservation in the background and waits until it is queued
+    async fn wait_in_queue(
+        scheduler: &Arc<MemoryScheduler>,
+        session_id: &'static str,
+        filename: &'static str,
+        bytes: u64,
+        granted: &mpsc::UnboundedSender<(&'static str, MemoryReservation)>,
+    ) {
+        let before = queued(scheduler);
+        let (scheduler_, granted) = (scheduler.clone(), granted.clone());
+        tokio::spawn(async move {
+            let cancel = CancelToken::default();
+            let reservation = scheduler_
+                .reserve(session_id, filename, bytes, &cancel)
+                .await
+                .unwrap();
+            let _ = granted.send((filename, reservation));
+        });
+        while queued(scheduler) == before {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn request_larger_than_the_budget_fails() {
+        let scheduler = Arc::new(MemoryScheduler::new(100));
+        let cancel = CancelToken::default();
+        let err = scheduler
+            .reserve("a", "huge.png", 101, &cancel)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "limit_exceeded");
+        assert!(scheduler
+            .reserve("a", "fits.png", 100, &cancel)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn release_wakes_waiters() {
+        let scheduler = Arc::new(MemoryScheduler::new(100));
+        let cancel = CancelToken::default();
+        let held = scheduler.reserve("a", "a.png", 80, &cancel).await.unwrap();
+        let (tx, mut granted) = mpsc::unbounded_channel();
+        wait_in_queue(&scheduler, "b", "b.png", 50, &tx).await;
+        assert!(granted.try_recv().is_err());
+
+        drop(held);
+        let (name, reservation) = granted.recv().await.unwrap();
+        assert_eq!(name, "b.png");
+        assert_eq!(scheduler.state.lock().unwrap().available, 50);
+        drop(reservation);
+        assert_eq!(scheduler.state.lock().unwrap().available, 100);
+    }
+
+    #[tokio::test]
+    async fn sessions_take_turns() {
+        let scheduler = Arc::new(MemoryScheduler::new(100));
+        let cancel = CancelToken::default();
+        let held = scheduler
+            .reserve("a", "a1.png", 100, &cancel)
+            .await
+            .unwrap();
+        let (tx, mut granted) = mpsc::unbounded_channel();
+        wait_in_queue(&scheduler, "a", "a2.png", 100, &tx).await;
+        wait_in_queue(&scheduler, "a", "a3.png", 100, &tx).await;
+        wait_in_queue(&scheduler, "b", "b1.png", 100, &tx).await;
+
+        drop(held);
+        let mut order = Vec::new();
+        while let Some((name, reservation)) = granted.recv().await {
+            order.push(name);
+            drop(reservation);
+            if order.len() == 3 {
+                break;
+            }
+        }
+        // The second session is served before the first session's later files
+        assert_eq!(order, ["a2.png", "b1.png", "a3.png"]);
+    }
+
+    #[tokio::test]
+    async fn canceled_waiter_leaves_the_queue() {
+        let scheduler = Arc::new(MemoryScheduler::new(100));
+        let held = scheduler
+            .reserve("a", "a.png", 100, &CancelToken::default())
+            .await
+            .unwrap();
+        let cancel = Arc::new(CancelToken::default());
+        let waiting = {
+            let (scheduler, cancel) = (scheduler.clone(), cancel.clone());
+            tokio::spawn(async move { scheduler.reserve("b", "b.png", 60, &cancel).await })
+        };
+        while queued(&scheduler) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        cancel.cancel_file("b.png");
+        let err = waiting.await.unwrap().err().unwrap();
+        assert_eq!(err.code(), "canceled");
+        assert_eq!(queued(&scheduler), 0);
+        drop(held);
+        assert_eq!(scheduler.state.lock().unwrap().available, 100);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::EngineError;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Session tokens handed out by `POST /api/sessions`.
+/// A session stays valid while it is used and expires after `ttl` of inactivity. At most
+/// `max_sessions` are kept; once that many are live, new ones are refused until some expire.
+#[derive(Clone)]
+pub struct SessionStore {
+    // Session ID -> last time it was issued or used
+    sessions: Arc<Mutex<HashMap<String, Instant>>>,
+    ttl: Duration,
+    max_sessions: usize,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration, max_sessions: usize) -> Self {
+        SessionStore {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+            max_sessions: max_sessions.max(1),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Creates a new random session ID. Live sessions are never evicted to make room, so
+    /// a full store refuses new ones instead.
+    pub fn issue(&self) -> Result<String, EngineError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.max_sessions {
+            sessions.retain(|_, last_used| last_used.elapsed() < self.ttl);
+        }
+        if sessions.len() >= self.max_sessions {
+            warn!(
+                "Session limit of {} reached, refusing a new session",
+                self.max_sessions
+            );
+            return Err(EngineError::TooManySessions(self.max_sessions));
+        }
+        let session_id = uuid::Uuid::new_v4().to_string();
+        sessions.insert(session_id.clone(), Instant::now());
+        Ok(session_id)
+    }
+
+    /// Checks that `session_id` was issued here and has not expired, and keeps it alive.
+    pub fn validate(&self, session_id: &str) -> Result<(), EngineError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(session_id) {
+            Some(last_used) if last_used.elapsed() < self.ttl => {
+                *last_used = Instant::now();
+                Ok(())
+            }
+            _ => Err(EngineError::UnknownSession(session_id.to_string())),
+        }
+    }
+
+    /// Drops sessions that have been idle for longer than the TTL.
+    pub fn purge_expired(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, last_used| last_used.elapsed() < self.ttl);
+        let removed = before - sessions.len();
+        if removed > 0 {
+            info!("🧹 Removed {} expired session(s)", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_sessions_validate_and_unknown_ones_do_not() {
+        let sessions = SessionStore::new(Duration::from_secs(60), 10);
+        let id = sessions.issue().unwrap();
+        assert!(sessions.validate(&id).is_ok());
+        let err = sessions.validate("session-abc").unwrap_err();
+        assert_eq!(err.code(), "unknown_session");
+    }
+
+    #[test]
+    fn expired_sessions_are_rejected_and_purged() {
+        let sessions = SessionStore::new(Duration::ZERO, 10);
+        let id = sessions.issue().unwrap();
+        assert!(sessions.validate(&id).is_err());
+        sessions.purge_expired();
+        assert!(sessions.sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn live_sessions_survive_a_flood_of_new_ones() {
+        let sessions = SessionStore::new(Duration::from_secs(60), 2);
+        let first = sessions.issue().unwrap();
+        let second = sessions.issue().unwrap();
+        for _ in 0..100 {
+            let err = sessions.issue().unwrap_err();
+            assert_eq!(err.code(), "too_many_sessions");
+        }
+        assert!(sessions.validate(&first).is_ok());
+        assert!(sessions.validate(&second).is_ok());
+    }
+
+    #[test]
+    fn full_store_makes_room_by_dropping_expired_sessions() {
+        let sessions = SessionStore::new(Duration::from_millis(20), 1);
+        let first = sessions.issue().unwrap();
+        assert!(sessions.issue().is_err());
+        std::thread::sleep(Duration::from_millis(30));
+
+        let second = sessions.issue().unwrap();
+        assert!(sessions.validate(&second).is_ok());
+        assert!(sessions.validate(&first).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::error::EngineError;
+use image::{DynamicImage, GrayImage, ImageFormat};
+
+// 8x8 windows every 4 pixels, as in the usual fast SSIM variants
+const WINDOW: u32 = 8;
+const STEP: u32 = 4;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Formats whose output can be decoded again to measure it. AVIF is not among them: the
+/// `image` crate only decodes AVIF through the system dav1d library, which the engine does
+/// not link, so `target_ssim` is rejected for AVIF output.
+pub const MEASURABLE_FORMATS: [&str; 2] = ["jpg", "webp"];
+
+/// Mean SSIM of the luma of two images of the same size: 1.0 for identical images,
+/// lower as they differ. Alpha is ignored, like the JPEG encoder does.
+pub fn ssim(reference: &DynamicImage, distorted: &DynamicImage) -> f64 {
+    let (a, b) = (reference.to_luma8(), distorted.to_luma8());
+    if a.dimensions() != b.dimensions() {
+        return 0.0;
+    }
+    let (width, height) = a.dimensions();
+    // Images smaller than a window are compared as one window
+    let (window_width, window_height) = (width.min(WINDOW), height.min(WINDOW));
+
+    let mut total = 0.0;
+    let mut windows = 0u64;
+    let mut y = 0;
+    while y + window_height <= height {
+        let mut x = 0;
+        while x + window_width <= width {
+            total += window_ssim(&a, &b, x, y, window_width, window_height);
+            windows += 1;
+            x += STEP;
+        }
+        y += STEP;
+    }
+    if windows == 0 {
+        return 1.0;
+    }
+    total / windows as f64
+}
+
+fn window_ssim(a: &GrayImage, b: &GrayImage, x0: u32, y0: u32, width: u32, height: u32) -> f64 {
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for y in y0..y0 + height {
+        for x in x0..x0 + width {
+            let pa = a.get_pixel(x, y)[0] as f64;
+            let pb = b.get_pixel(x, y)[0] as f64;
+            sum_a += pa;
+            sum_b += pb;
+            sum_aa += pa * pa;
+            sum_bb += pb * pb;
+            sum_ab += pa * pb;
+        }
+    }
+    let n = (width * height) as f64;
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+/// Parses a requested SSIM target, which must be above 0 and at most 1.
+pub fn parse_target(value: &str) -> Result<f64, EngineError> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|target| *target > 0.0 && *target <= 1.0)
+        .ok_or_else(|| {
+            EngineError::InvalidRequest(format!(
+                "Invalid target_ssim '{}' (expected a number above 0 and at most 1, e.g. 0.98)",
+                value
+            ))
+        })
+}
+
+/// Decodes encoder output of `format` again, to compare it with its source.
+pub fn decode_output(format: &str, data: &[u8]) -> Result<DynamicImage, EngineError> {
+    match format {
+        "jpg" => image::load_from_memory_with_format(data, ImageFormat::Jpeg)
+            .map_err(|e| EngineError::encode(format, e)),
+        "webp" => webp::Decoder::new(data)
+            .decode()
+            .map(|image| image.to_image())
+            .ok_or_else(|| EngineError::encode(format, "cannot decode the output to measure it")),
+        other => Err(EngineError::InvalidRequest(format!(
+            "Cannot measure SSIM of {} output",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Luma, RgbImage};
+
+    fn pattern(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let value = ((x * 255 / width + y * 3) % 256) as u8;
+            image::Rgb([value, value / 2, 255 - value])
+        }))
+    }
+
+    // Adds a deterministic +-amplitude offset to the luma of every pixel
+    fn noisy(image: &DynamicImage, amplitude: i32) -> DynamicImage {
+        let mut luma = image.to_luma8();
+        for (x, y, pixel) in luma.enumerate_pixels_mut() {
+            let offset = ((x * 31 + y * 17) % 3) as i32 - 1;
+            pixel[0] = (pixel[0] as i32 + offset * amplitude).clamp(0, 255) as u8;
+        }
+        DynamicImage::ImageLuma8(luma)
+    }
+
+    #[test]
+    fn identical_images_score_one() {
+        let image = pattern(32, 24);
+        assert!((ssim(&image, &image) - 1.0).abs() < 1e-9);
+        // Smaller than a window, and a flat image without any variance
+        let tiny = pattern(3, 5);
+        assert!((ssim(&tiny, &tiny) - 1.0).abs() < 1e-9);
+        let flat = DynamicImage::ImageLuma8(GrayImage::from_pixel(16, 16, Luma([90])));
+        assert!((ssim(&flat, &flat) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn more_noise_scores_lower() {
+        let image = pattern(32, 32);
+        let slight = ssim(&image, &noisy(&image, 4));
+        let strong = ssim(&image, &noisy(&image, 40));
+        assert!(slight < 1.0, "{}", slight);
+        assert!(strong < slight, "{} vs {}", strong, slight);
+        assert!(strong > 0.0);
+    }
+
+    #[test]
+    fn different_sizes_score_zero() {
+        assert_eq!(ssim(&pattern(16, 16), &pattern(16, 8)), 0.0);
+    }
+
+    #[test]
+    fn targets_must_be_above_zero_and_at_most_one() {
+        assert_eq!(parse_target("0.98").unwrap(), 0.98);
+        assert_eq!(parse_target(" 1 ").unwrap(), 1.0);
+        for value in ["0", "-0.5", "1.01", "high", "", "NaN"] {
+            assert!(
+                matches!(parse_target(value), Err(EngineError::InvalidRequest(_))),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn only_jpg_and_webp_output_is_decoded_again() {
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        pattern(16, 16)
+            .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        assert_eq!(
+            decode_output("jpg", jpeg.get_ref()).unwrap().dimensions(),
+            (16, 16)
+        );
+        assert!(matches!(
+            decode_output("avif", b"anything"),
+            Err(EngineError::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+use crate::error::EngineError;
+use crate::resize::{self, Gravity};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use std::str::FromStr;
+
+/// One step of a transform list, applied in order before the image is resized.
+///
+/// Written as `op:args`: `crop:X,Y,WIDTH,HEIGHT`, `crop:16:9[,GRAVITY]`,
+/// `rotate:DEGREES[,RRGGBB]` and `flip:h` / `flip:v`. Lists are separated by `;`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    /// Cut out a rectangle, in pixels of the (auto-oriented) image.
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Cut out the largest region with this aspect ratio, anchored at the gravity.
+    CropAspect {
+        width: u32,
+        height: u32,
+        gravity: Gravity,
+    },
+    /// Rotate clockwise. Multiples of 90 are lossless; other angles enlarge the canvas and
+    /// fill the corners with `background`, or the request's background when it is not set.
+    Rotate {
+        degrees: f64,
+        background: Option<Rgba<u8>>,
+    },
+    FlipHorizontal,
+    FlipVertical,
+}
+
+impl FromStr for Transform {
+    type Err = EngineError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = |expected: &str| {
+            EngineError::InvalidRequest(format!(
+                "Invalid transform '{}' (expected {})",
+                value, expected
+            ))
+        };
+        let (op, args) = value.trim().split_once(':').unwrap_or((value.trim(), ""));
+        let args: Vec<&str> = args.split(',').map(str::trim).collect();
+        let number = |arg: &str, expected: &str| arg.parse::<u32>().map_err(|_| invalid(expected));
+
+        match op {
+            // An aspect ratio is written with a second colon, e.g. crop:16:9
+            "crop" if args[0].contains(':') => {
+                const EXPECTED: &str = "crop:WIDTH:HEIGHT[,GRAVITY]";
+                let (width, height) = args[0].split_once(':').expect("checked above");
+                let (width, height) = (number(width, EXPECTED)?, number(height, EXPECTED)?);
+                if width == 0 || height == 0 || args.len() > 2 {
+                    return Err(invalid(EXPECTED));
+                }
+                let gravity = args.get(1).map(|g| g.parse()).transpose()?;
+                Ok(Transform::CropAspect {
+                    width,
+                    height,
+                    gravity: gravity.unwrap_or_default(),
+                })
+            }
+            "crop" => {
+                const EXPECTED: &str = "crop:X,Y,WIDTH,HEIGHT";
+                let [x, y, width, height] = args[..] else {
+                    return Err(invalid(EXPECTED));
+                };
+                let (width, height) = (number(width, EXPECTED)?, number(height, EXPECTED)?);
+                if width == 0 || height == 0 {
+                    return Err(invalid(EXPECTED));
+                }
+                Ok(Transform::Crop {
+                    x: number(x, EXPECTED)?,
+                    y: number(y, EXPECTED)?,
+                    width,
+                    height,
+                })
+            }
+            "rotate" => {
+                const EXPECTED: &str = "rotate:DEGREES[,RRGGBB]";
+                let degrees = args[0]
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|d| d.is_finite())
+                    .ok_or_else(|| invalid(EXPECTED))?;
+                if args.len() > 2 {
+                    return Err(invalid(EXPECTED));
+                }
+                let background = args.get(1).map(|c| resize::parse_color(c)).transpose()?;
+                Ok(Transform::Rotate {
+                    degrees,
+                    background,
+                })
+            }
+            "flip" => match args[..] {
+                ["h" | "horizontal"] => Ok(Transform::FlipHorizontal),
+                ["v" | "vertical"] => Ok(Transform::FlipVertical),
+                _ => Err(invalid("flip:h or flip:v")),
+            },
+            _ => Err(invalid("crop, rotate or flip")),
+        }
+    }
+}
+
+/// Parses a `;`-separated transform list; empty entries are skipped.
+pub fn parse_list(value: &str) -> Result<Vec<Transform>, EngineError> {
+    value
+        .split(';')
+        .filter(|op| !op.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Applies `transforms` in order. `background` fills the corners of rotations that set none.
+pub fn apply(
+    mut img: DynamicImage,
+    transforms: &[Transform],
+    background: Rgba<u8>,
+    filename: &str,
+) -> Result<DynamicImage, EngineError> {
+    for transform in transforms {
+        img = match *transform {
+            Transform::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (img_width, img_height) = img.dimensions();
+                if x as u64 + width as u64 > img_width as u64
+                    || y as u64 + height as u64 > img_height as u64
+                {
+                    return Err(EngineError::InvalidRequest(format!(
+                        "Crop {}x{} at {},{} does not fit '{}' ({}x{})",
+                        width, height, x, y, filename, img_width, img_height
+                    )));
+                }
+                img.crop_imm(x, y, width, height)
+            }
+            Transform::CropAspect {
+                width,
+                height,
+                gravity,
+            } => {
+                let region = aspect_region(img.dimensions(), (width, height));
+                let (x, y) = gravity.offset(img.dimensions(), region);
+                img.crop_imm(x, y, region.0, region.1)
+            }
+            Transform::Rotate {
+                degrees,
+                background: fill,
+            } => match quarter_turns(degrees) {
+                Some(0) => img,
+                Some(1) => img.rotate90(),
+                Some(2) => img.rotate180(),
+                Some(_) => img.rotate270(),
+                None => rotate(&img, degrees, fill.unwrap_or(background)),
+            },
+            Transform::FlipHorizontal => img.fliph(),
+            Transform::FlipVertical => img.flipv(),
+        };
+    }
+    Ok(img)
+}
+
+/// Dimensions after `transforms`, and the most pixels one step holds at once.
+pub fn planned_size(transforms: &[Transform], source: (u32, u32)) -> ((u32, u32), u64) {
+    let pixels = |(width, height): (u32, u32)| width as u64 * height as u64;
+    let mut size = source;
+    let mut peak = 0;
+    for transform in transforms {
+        let input = size;
+        size = match *transform {
+            Transform::Crop { width, height, .. } => (width.min(size.0), height.min(size.1)),
+            Transform::CropAspect { width, height, .. } => aspect_region(size, (width, height)),
+            Transform::Rotate { degrees, .. } => match quarter_turns(degrees) {
+                Some(1 | 3) => (size.1, size.0),
+                Some(_) => size,
+                None => rotated_size(size, degrees),
+            },
+            Transform::FlipHorizontal | Transform::FlipVertical => size,
+        };
+        // Free rotation samples from an RGBA copy of its input
+        let copy = match *transform {
+            Transform::Rotate { degrees, .. } if quarter_turns(degrees).is_none() => input,
+            _ => (0, 0),
+        };
+        peak = peak.max(pixels(size) + pixels(copy));
+    }
+    (size, peak)
+}
+
+// Largest region of `size` with the `aspect` ratio
+fn aspect_region(size: (u32, u32), aspect: (u32, u32)) -> (u32, u32) {
+    let (width, height) = (size.0 as u64, size.1 as u64);
+    let (aspect_width, aspect_height) = (aspect.0 as u64, aspect.1 as u64);
+    if width * aspect_height >= height * aspect_width {
+        (
+            (height * aspect_width / aspect_height).max(1) as u32,
+            size.1,
+        )
+    } else {
+        (size.0, (width * aspect_height / aspect_width).max(1) as u32)
+    }
+}
+
+// Clockwise quarter turns (0-3) when `degrees` is a multiple of 90
+fn quarter_turns(degrees: f64) -> Option<u32> {
+    let turns = degrees.rem_euclid(360.0) / 90.0;
+    ((turns - turns.round()).abs() < 1e-9).then_some(turns.round() as u32 % 4)
+}
+
+// Bounding box of the image rotated by `degrees`
+fn rotated_size(size: (u32, u32), degrees: f64) -> (u32, u32) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (size.0 as f64, size.1 as f64);
+    // The epsilon keeps float noise from adding a row or column
+    let fit = |v: f64| ((v - 1e-6).ceil() as u32).max(1);
+    (
+        fit(width * cos.abs() + height * sin.abs()),
+        fit(width * sin.abs() + height * cos.abs()),
+    )
+}
+
+// Rotates by an arbitrary angle with bilinear sampling, filling the uncovered corners
+fn rotate(img: &DynamicImage, degrees: f64, background: Rgba<u8>) -> DynamicImage {
+    let opaque = background[3] == u8::MAX && !img.color().has_alpha();
+    let source = img.to_rgba8();
+    let (width, height) = source.dimensions();
+    let (out_width, out_height) = rotated_size((width, height), degrees);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let center = (width as f64 / 2.0, height as f64 / 2.0);
+    let out_center = (out_width as f64 / 2.0, out_height as f64 / 2.0);
+
+    let canvas = RgbaImage::from_fn(out_width, out_height, |x, y| {
+        // Map each output pixel center back into the source
+        let dx = x as f64 + 0.5 - out_center.0;
+        let dy = y as f64 + 0.5 - out_center.1;
+        let sx = dx * cos + dy * sin + center.0 - 0.5;
+        let sy = -dx * sin + dy * cos + center.1 - 0.5;
+        sample(&source, sx, sy, background)
+    });
+    resize::canvas_image(canvas, opaque)
+}
+
+// Bilinear sample at (x, y); neighbours outside the image count as `background`
+fn sample(img: &RgbaImage, x: f64, y: f64, background: Rgba<u8>) -> Rgba<u8> {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |px: f64, py: f64| {
+        if px < 0.0 || py < 0.0 || px >= img.width() as f64 || py >= img.height() as f64 {
+            background
+        } else {
+            *img.get_pixel(px as u32, py as u32)
+        }
+    };
+    let corners = [
+        (pixel(x0, y0), (1.0 - fx) * (1.0 - fy)),
+        (pixel(x0 + 1.0, y0), fx * (1.0 - fy)),
+        (pixel(x0, y0 + 1.0), (1.0 - fx) * fy),
+        (pixel(x0 + 1.0, y0 + 1.0), fx * fy),
+    ];
+    let mut out = [0u8; 4];
+    for (channel, value) in out.iter_mut().enumerate() {
+        let sum: f64 = corners
+            .iter()
+            .map(|(p, weight)| p[channel] as f64 * weight)
+            .sum();
+        *value = sum.round().clamp(0.0, 255.0) as u8;
+    }
+    Rgba(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aspect_region_is_the_largest_fitting_box() {
+        // Landscape and portrait sources, wide and tall ratios
+        assert_eq!(aspect_region((400, 200), (1, 1)), (200, 200));
+        assert_eq!(aspect_region((200, 400), (1, 1)), (200, 200));
+        assert_eq!(aspect_region((1920, 1440), (16, 9)), (1920, 1080));
+        assert_eq!(aspect_region((1080, 1920), (16, 9)), (1080, 607));
+        assert_eq!(aspect_region((1080, 1920), (9, 16)), (1080, 1920));
+        // Never empty, even for extreme ratios
+        assert_eq!(aspect_region((10, 10), (1000, 1)), (10, 1));
+    }
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    // 4x2 white image with a red pixel in the top-left corner
+    fn marked() -> DynamicImage {
+        let mut img = RgbaImage::from_pixel(4, 2, WHITE);
+        img.put_pixel(0, 0, RED);
+        DynamicImage::ImageRgba8(img)
+    }
+
+    fn red_pixels(img: &DynamicImage) -> Vec<(u32, u32)> {
+        img.to_rgba8()
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| **pixel == RED)
+            .map(|(x, y, _)| (x, y))
+            .collect()
+    }
+
+    fn run(list: &str) -> Result<DynamicImage, EngineError> {
+        apply(marked(), &parse_list(list)?, WHITE, "test.png")
+    }
+
+    #[test]
+    fn lists_parse_in_order() {
+        assert_eq!(
+            parse_list(" crop:1:1 ; rotate:-90,#000000;flip:h;; crop:1,2,3,4").unwrap(),
+            [
+                Transform::CropAspect {
+                    width: 1,
+                    height: 1,
+                    gravity: Gravity::Center,
+                },
+                Transform::Rotate {
+                    degrees: -90.0,
+                    background: Some(Rgba([0, 0, 0, 255])),
+                },
+                Transform::FlipHorizontal,
+                Transform::Crop {
+                    x: 1,
+                    y: 2,
+                    width: 3,
+                    height: 4,
+                },
+            ]
+        );
+        for value in [
+            "crop:0,0,0,10",
+            "crop:1,2,3",
+            "crop:16:0",
+            "crop:16:9,center,extra",
+            "rotate:abc",
+            "rotate:NaN",
+            "rotate:inf",
+            "rotate:90,#fff,extra",
+            "flip:x",
+            "blur:3",
+        ] {
+            let err = parse_list(value).unwrap_err();
+            assert_eq!(err.code(), "invalid_request", "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn steps_apply_in_the_given_order() {
+        // Crop first keeps the marked corner, rotating first moves it out of the crop
+        let cropped_then_rotated = run("crop:0,0,2,2;rotate:180").unwrap();
+        assert_eq!(cropped_then_rotated.dimensions(), (2, 2));
+        assert_eq!(red_pixels(&cropped_then_rotated), [(1, 1)]);
+        let rotated_then_cropped = run("rotate:180;crop:0,0,2,2").unwrap();
+        assert_eq!(red_pixels(&rotated_then_cropped), []);
+
+        // Flipping and rotating do not commute either
+        assert_eq!(red_pixels(&run("flip:h;rotate:90").unwrap()), [(1, 3)]);
+        assert_eq!(red_pixels(&run("rotate:90;flip:h").unwrap()), [(0, 0)]);
+        assert_eq!(red_pixels(&run("flip:v").unwrap()), [(0, 1)]);
+    }
+
+    #[test]
+    fn rotations_are_taken_modulo_a_full_turn() {
+        let quarter = run("rotate:270").unwrap().to_rgba8();
+        for equivalent in ["rotate:-90", "rotate:630", "rotate:-450"] {
+            assert_eq!(
+                run(equivalent).unwrap().to_rgba8(),
+                quarter,
+                "{}",
+                equivalent
+            );
+        }
+        assert_eq!(red_pixels(&run("rotate:360").unwrap()), [(0, 0)]);
+        assert_eq!(
+            run("rotate:-30").unwrap().to_rgba8(),
+            run("rotate:330").unwrap().to_rgba8()
+        );
+        assert_eq!(quarter_turns(-90.0), Some(3));
+        assert_eq!(quarter_turns(720.0), Some(0));
+        assert_eq!(quarter_turns(45.0), None);
+    }
+
+    #[test]
+    fn free_rotation_enlarges_the_canvas_and_fills_the_corners() {
+        let img =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(20, 10, image::Rgb([0, 0, 255])));
+        let black = Rgba([0, 0, 0, 255]);
+        let rotated = apply(img, &parse_list("rotate:45").unwrap(), black, "a.png").unwrap();
+        assert_eq!(rotated.dimensions(), rotated_size((20, 10), 45.0));
+        assert_eq!(rotated.dimensions(), (22, 22));
+        // Opaque input and fill keep the image opaque
+        assert_eq!(rotated.color(), image::ColorType::Rgb8);
+        let rotated = rotated.to_rgb8();
+        assert_eq!(rotated.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(rotated.get_pixel(11, 11).0, [0, 0, 255]);
+    }
+
+    #[test]
+    fn crop_outside_the_image_is_rejected() {
+        for list in ["crop:3,0,2,2", "crop:0,1,4,2", "crop:4294967295,0,1,1"] {
+            let err = run(list).unwrap_err();
+            assert_eq!(err.code(), "invalid_request", "{}", list);
+        }
+        // Touching the edge is fine
+        assert_eq!(run("crop:2,0,2,2").unwrap().dimensions(), (2, 2));
+    }
+
+    #[test]
+    fn planned_size_matches_the_result() {
+        for list in [
+            "crop:1:1,east;rotate:90",
+            "rotate:30;crop:0,0,3,3",
+            "crop:16:9;flip:v;rotate:-90",
+        ] {
+            let transforms = parse_list(list).unwrap();
+            let (size, peak) = planned_size(&transforms, (4, 2));
+            let img = apply(marked(), &transforms, WHITE, "a.png").unwrap();
+            assert_eq!(img.dimensions(), size, "{}", list);
+            assert!(peak >= size.0 as u64 * size.1 as u64);
+        }
+    }
+}
@@ -2,6 +2,14 @@ use actix::ActorContext;
 use actix::{Actor, Addr, AsyncContext, Message, StreamHandler};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
+use log::debug;
+
+use crate::handlers::{AppState, SessionQuery};
+use crate::metrics::METRICS;
+use crate::progress::{
+    CommandAck, ProgressEvent, ProgressReporter, ProgressSink, PROTOCOL_VERSION,
+};
+use crate::sessions::SessionStore;
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
@@ -14,11 +22,71 @@ pub type ProgressChannels = Arc<Mutex<HashMap<String, Addr<MyWebSocket>>>>;
 #[rtype(result = "()")]
 pub struct ProgressMessage(pub String);
 
+/// Forwards a batch's progress events to the WebSocket of its session.
+pub struct WsProgress {
+    session_id: String,
+    channels: ProgressChannels,
+}
+
+impl WsProgress {
+    /// Reporter for `session_id`, sending to whichever socket is connected for it.
+    pub fn reporter(session_id: &str, channels: &ProgressChannels) -> ProgressReporter {
+        ProgressReporter::new(
+            session_id,
+            WsProgress {
+                session_id: session_id.to_string(),
+                channels: channels.clone(),
+            },
+        )
+    }
+}
+
+impl ProgressSink for WsProgress {
+    fn send(&self, event: &ProgressEvent) {
+        let payload = match serde_json::to_string(event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                debug!("Failed to serialize progress event: {}", e);
+                return;
+            }
+        };
+
+        match self.channels.lock().unwrap().get(&self.session_id) {
+            Some(addr) => {
+                let _ = addr.try_send(ProgressMessage(payload));
+            }
+            None => debug!("No WebSocket client for session_id: {}", self.session_id),
+        }
+    }
+}
+
 // WebSocket actor
 pub struct MyWebSocket {
     // <-- PUBLIC STRUCT
     pub session_id: String,
     pub channels: ProgressChannels,
+    pub state: AppState,
+}
+
+impl MyWebSocket {
+    // Runs a text command against this socket's session and builds the reply
+    fn run_command<'a>(&self, text: &'a str) -> Option<CommandAck<'a>> {
+        let (ack, filename, accepted) = if text == "cancel" {
+            ("cancel", None, self.state.cancel_session(&self.session_id))
+        } else if let Some(filename) = text.strip_prefix("cancel_file:") {
+            let accepted = self.state.cancel_file(&self.session_id, filename);
+            ("cancel_file", Some(filename), accepted)
+        } else {
+            return None;
+        };
+
+        Some(CommandAck {
+            version: PROTOCOL_VERSION,
+            ack,
+            filename,
+            accepted,
+        })
+    }
 }
 
 impl Actor for MyWebSocket {
@@ -27,16 +95,17 @@ impl Actor for MyWebSocket {
     fn started(&mut self, ctx: &mut Self::Context) {
         println!("Client connected: {}", self.session_id);
         // Register session
-        self.channels
-            .lock()
-            .unwrap()
-            .insert(self.session_id.clone(), ctx.address());
+        let mut channels = self.channels.lock().unwrap();
+        channels.insert(self.session_id.clone(), ctx.address());
+        METRICS.websocket_sessions.set(channels.len() as i64);
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
         println!("Client disconnected: {}", self.session_id);
         // Remove session
-        self.channels.lock().unwrap().remove(&self.session_id);
+        let mut channels = self.channels.lock().unwrap();
+        channels.remove(&self.session_id);
+        METRICS.websocket_sessions.set(channels.len() as i64);
     }
 }
 
@@ -45,8 +114,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebSocket {
         match msg {
             Ok(ws::Message::Text(text)) => {
                 println!("Received: {}", text);
-                if text == "cancel" {
-                    // Handle cancel here if you want
+                match self.run_command(text.trim()) {
+                    Some(ack) => match serde_json::to_string(&ack) {
+                        Ok(reply) => ctx.text(reply),
+                        Err(e) => debug!("Failed to serialize command ack: {}", e),
+                    },
+                    None => debug!("Ignoring unknown command: {}", text),
                 }
             }
             Ok(ws::Message::Close(_)) => {
@@ -69,15 +142,107 @@ impl actix::Handler<ProgressMessage> for MyWebSocket {
 pub async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
+    web::Query(session_query): web::Query<SessionQuery>,
     channels: web::Data<ProgressChannels>,
+    state: web::Data<AppState>,
+    sessions: web::Data<SessionStore>,
 ) -> Result<HttpResponse, Error> {
-    let query = req.query_string();
-    let session_id = query.split('=').nth(1).unwrap_or("unknown").to_string();
+    // Only sessions issued by this server can be subscribed to
+    sessions.validate(&session_query.session_id)?;
 
     let ws = MyWebSocket {
-        session_id,
+        session_id: session_query.session_id,
         channels: channels.get_ref().clone(),
+        state: state.get_ref().clone(),
     };
 
     ws::start(ws, &req, stream)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::MemoryScheduler;
+    use actix_web::{dev, error::PayloadError, test as actix_test, FromRequest};
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    fn socket(state: &AppState) -> MyWebSocket {
+        MyWebSocket {
+            session_id: "session".to_string(),
+            channels: ProgressChannels::default(),
+            state: state.clone(),
+        }
+    }
+
+    #[test]
+    fn cancel_command_cancels_the_running_batch() {
+        let state = AppState::new(MemoryScheduler::new(1024));
+        let token = state.register_session("session");
+        let other = state.register_session("other");
+
+        let ack = socket(&state).run_command("cancel").unwrap();
+        assert_eq!(
+            (ack.ack, ack.filename, ack.accepted),
+            ("cancel", None, true)
+        );
+        assert!(token.is_canceled());
+        assert!(!other.is_canceled());
+    }
+
+    #[test]
+    fn cancel_file_command_drops_one_file() {
+        let state = AppState::new(MemoryScheduler::new(1024));
+        let token = state.register_session("session");
+
+        let ack = socket(&state).run_command("cancel_file:b.png").unwrap();
+        assert_eq!(
+            (ack.ack, ack.filename, ack.accepted),
+            ("cancel_file", Some("b.png"), true)
+        );
+        assert!(token.is_file_canceled("b.png"));
+        assert!(!token.is_canceled());
+    }
+
+    #[test]
+    fn commands_without_a_running_batch_are_not_accepted() {
+        let state = AppState::new(MemoryScheduler::new(1024));
+        let ack = socket(&state).run_command("cancel").unwrap();
+        assert!(!ack.accepted);
+        assert!(socket(&state).run_command("pause").is_none());
+    }
+
+    #[actix_web::test]
+    async fn connected_sockets_are_counted_in_the_metrics() {
+        let state = AppState::new(MemoryScheduler::new(1024));
+        let socket = socket(&state);
+        let channels = socket.channels.clone();
+        let (req, _) = actix_test::TestRequest::get()
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_http_parts();
+        // The client side stays open until `client` is dropped
+        let (client, stream) = futures::channel::mpsc::unbounded::<Result<Bytes, PayloadError>>();
+        let mut payload: dev::Payload = dev::Payload::Stream {
+            payload: Box::pin(stream),
+        };
+        let payload = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+
+        let response = ws::start(socket, &req, payload).unwrap();
+        assert_eq!(response.status(), 101);
+        // The actor runs while its response body is polled
+        let body = actix_web::rt::spawn(actix_web::body::to_bytes(response.into_body()));
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        assert!(channels.lock().unwrap().contains_key("session"));
+        assert_eq!(METRICS.websocket_sessions.get(), 1);
+
+        drop(client);
+        let _ = body.await;
+        assert!(channels.lock().unwrap().is_empty());
+        assert_eq!(METRICS.websocket_sessions.get(), 0);
+    }
+}